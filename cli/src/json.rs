@@ -0,0 +1,134 @@
+use jabber_client::{
+    message_payload,
+    state::{Message, MessageMeta, MessagePayload, MetaKind, PublicKey, ThreadState},
+    JabberAccount,
+};
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+
+/// Decodes the data of the account at `address` into JSON, keeping the raw data if it is not a
+/// valid Jabber account.
+pub fn account(address: &Pubkey, data: &[u8]) -> Value {
+    match JabberAccount::unpack(data) {
+        Ok(account) => json!({
+            "address": address.to_string(),
+            "kind": format!("{:?}", account.kind()),
+            "data": account_data(&account),
+        }),
+        Err(err) => json!({
+            "address": address.to_string(),
+            "error": err.to_string(),
+            "raw": base64::encode(data),
+        }),
+    }
+}
+
+fn account_data(account: &JabberAccount) -> Value {
+    match account {
+        JabberAccount::Profile(profile) => json!({
+            "name": profile.name,
+            "bio": profile.bio,
+            "lamports_per_message": profile.lamports_per_message,
+            "thread_tail_pk": opt_pk(&profile.thread_tail_pk),
+            "encryption_key": opt_pk(&profile.encryption_key),
+            "encryption_key_version": profile.encryption_key_version,
+        }),
+        JabberAccount::Thread(thread) => json!({
+            "msg_count": thread.msg_count,
+            "prev_thread_u1_pk": opt_pk(&thread.prev_thread_u1_pk),
+            "prev_thread_u2_pk": opt_pk(&thread.prev_thread_u2_pk),
+            "u1_pk": pk(&thread.u1_pk),
+            "u2_pk": pk(&thread.u2_pk),
+            "u1_ban": thread.u1_ban,
+            "u2_ban": thread.u2_ban,
+            "u1_state": thread_state(&thread.u1_state),
+            "u2_state": thread_state(&thread.u2_state),
+        }),
+        JabberAccount::Message(message) => json!({
+            "kind": message.kind,
+            "msg": payload(message),
+            "timestamp": message.timestamp,
+            "ttl": message.ttl,
+            "edited": message.edited,
+            "prev_msg": opt_pk(&message.prev_msg),
+            "meta": message.meta.iter().map(meta).collect::<Vec<_>>(),
+        }),
+        JabberAccount::Jabber(jabber) => json!({
+            "unregistered_thread_tail_pk": opt_pk(&jabber.unregistered_thread_tail_pk),
+        }),
+        JabberAccount::Group(group) => json!({
+            "creator": pk(&group.creator),
+            "owner": pk(&group.owner),
+            "admins": pks(&group.admins),
+            "posters": pks(&group.posters),
+            "members": pks(&group.members),
+            "post_policy": format!("{:?}", group.post_policy),
+            "max_msg_len": group.max_msg_len,
+            "capacity": group.capacity,
+            "msg_count": group.msg_count,
+        }),
+        JabberAccount::Channel(channel) => json!({
+            "publisher": pk(&channel.publisher),
+            "name": channel.name,
+            "subscriber_count": channel.subscriber_count,
+            "msg_count": channel.msg_count,
+        }),
+        JabberAccount::Subscription(subscription) => json!({
+            "channel": pk(&subscription.channel),
+            "subscriber": pk(&subscription.subscriber),
+            "since": subscription.since,
+        }),
+    }
+}
+
+/// Typed payloads are decoded, anything else is kept as base64.
+fn payload(message: &Message) -> Value {
+    match message_payload(message) {
+        Ok(Some(MessagePayload::Text(text))) => json!({ "text": text }),
+        Ok(Some(MessagePayload::Cta(cta))) => json!({
+            "title": cta.title,
+            "body": cta.body,
+            "delivery": cta.delivery,
+        }),
+        Ok(Some(MessagePayload::Plain(data))) => {
+            json!({ "plain": String::from_utf8_lossy(&data) })
+        }
+        Ok(Some(MessagePayload::Encrypted(encrypted))) => json!({
+            "key_version": encrypted.key_version,
+            "data": base64::encode(&encrypted.data),
+        }),
+        Ok(None) | Err(_) => json!({ "raw": base64::encode(&message.msg) }),
+    }
+}
+
+fn meta(meta: &MessageMeta) -> Value {
+    let kind = match meta.kind {
+        MetaKind::Reaction(code) => json!({ "reaction": code }),
+        MetaKind::Read => json!("read"),
+    };
+    json!({
+        "by_sender": meta.by_sender,
+        "kind": kind,
+        "timestamp": meta.timestamp,
+    })
+}
+
+fn thread_state(state: &ThreadState) -> Value {
+    json!({
+        "last_read": state.last_read,
+        "muted": state.muted,
+        "archived": state.archived,
+    })
+}
+
+fn pk(key: &PublicKey) -> String {
+    Pubkey::new(key).to_string()
+}
+
+fn opt_pk(key: &Option<PublicKey>) -> Value {
+    json!(key.as_ref().map(pk))
+}
+
+fn pks(keys: &[PublicKey]) -> Vec<String> {
+    keys.iter().map(pk).collect()
+}
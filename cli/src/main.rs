@@ -0,0 +1,437 @@
+//! Command line client for the Jabber program.
+
+mod json;
+
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
+use crypto_box::{
+    aead::{generic_array::GenericArray, Aead},
+    SalsaBox, SecretKey,
+};
+use jabber_client::{
+    instruction, message_payload,
+    state::{EncryptedMessage, Message, MessagePayload, Profile, PublicKey, Serdes, Thread},
+    AccountSource, MessageHistory, MessageSlot, SourceError, Threads,
+};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    instruction::Instruction,
+    pubkey::Pubkey,
+    signature::{read_keypair_file, Keypair, Signer},
+    transaction::Transaction,
+};
+use std::{
+    error::Error,
+    fs,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+type CliResult<T> = Result<T, Box<dyn Error>>;
+
+struct Config {
+    rpc: RpcClient,
+    program_id: Pubkey,
+    keypair_path: String,
+}
+
+impl Config {
+    fn keypair(&self) -> CliResult<Keypair> {
+        read_keypair_file(&self.keypair_path)
+            .map_err(|err| format!("Can not read keypair {}: {}", self.keypair_path, err).into())
+    }
+
+    /// The `user` argument, or the configured keypair.
+    fn user(&self, matches: &ArgMatches) -> CliResult<Pubkey> {
+        match matches.value_of("user") {
+            Some(user) => Ok(Pubkey::from_str(user)?),
+            None => Ok(self.keypair()?.pubkey()),
+        }
+    }
+
+    fn account_data(&self, address: &Pubkey) -> CliResult<Option<Vec<u8>>> {
+        Ok(self
+            .rpc
+            .get_account_with_commitment(address, CommitmentConfig::default())?
+            .value
+            .map(|account| account.data))
+    }
+
+    fn account<T: Serdes>(&self, address: &Pubkey) -> CliResult<Option<T>> {
+        match self.account_data(address)? {
+            Some(data) if T::is_initialized(&data) => Ok(Some(T::unpack(&data)?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn send(&self, signer: &Keypair, instruction: Instruction) -> CliResult<()> {
+        let (recent_blockhash, _) = self.rpc.get_recent_blockhash()?;
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&signer.pubkey()),
+            &[signer],
+            recent_blockhash,
+        );
+        let signature = self.rpc.send_and_confirm_transaction(&transaction)?;
+        println!("{}", signature);
+        Ok(())
+    }
+}
+
+impl AccountSource for Config {
+    fn get_account_data(&self, address: &Pubkey) -> Result<Option<Vec<u8>>, SourceError> {
+        self.account_data(address)
+            .map_err(|err| err.to_string().into())
+    }
+
+    fn get_multiple_account_data(
+        &self,
+        addresses: &[Pubkey],
+    ) -> Result<Vec<Option<Vec<u8>>>, SourceError> {
+        Ok(self
+            .rpc
+            .get_multiple_accounts(addresses)?
+            .into_iter()
+            .map(|account| account.map(|account| account.data))
+            .collect())
+    }
+}
+
+/// Reads an X25519 secret key stored as a JSON array of 32 bytes.
+fn read_encryption_key(path: &str) -> CliResult<SecretKey> {
+    let bytes: Vec<u8> = serde_json::from_str(&fs::read_to_string(path)?)?;
+    if bytes.len() != 32 {
+        return Err(format!("{} is not a 32 byte X25519 secret key", path).into());
+    }
+    let mut key = [0; 32];
+    key.copy_from_slice(&bytes);
+    Ok(SecretKey::from(key))
+}
+
+/// Box between our secret key and the other user's encryption key. Messages use the first 24
+/// bytes of the message address as the nonce.
+fn encryption_box(secret_key: &SecretKey, encryption_key: &PublicKey) -> SalsaBox {
+    SalsaBox::new(&(*encryption_key).into(), secret_key)
+}
+
+fn nonce(msg_pk: &Pubkey) -> &GenericArray<u8, <SalsaBox as Aead>::NonceSize> {
+    GenericArray::from_slice(&msg_pk.as_ref()[..24])
+}
+
+fn set_profile(config: &Config, matches: &ArgMatches) -> CliResult<()> {
+    let signer = config.keypair()?;
+    let encryption_key = match matches.value_of("encryption_keypair") {
+        Some(path) => Some(*read_encryption_key(path)?.public_key().as_bytes()),
+        None => None,
+    };
+    let instruction = instruction::set_user_profile(
+        &config.program_id,
+        &signer.pubkey(),
+        matches.value_of("name").map(String::from),
+        matches.value_of("bio").map(String::from),
+        matches
+            .value_of("lamports_per_message")
+            .map(u64::from_str)
+            .transpose()?,
+        encryption_key,
+    )?;
+    config.send(&signer, instruction)
+}
+
+fn send_message(config: &Config, matches: &ArgMatches) -> CliResult<()> {
+    let signer = config.keypair()?;
+    let sender_pk = signer.pubkey();
+    let receiver_pk = Pubkey::from_str(matches.value_of("receiver").unwrap())?;
+    let text = matches.value_of("message").unwrap();
+    let program_id = &config.program_id;
+
+    let thread_pk = Thread::find_address(&sender_pk, &receiver_pk, program_id).0;
+    let msg_index = config
+        .account::<Thread>(&thread_pk)?
+        .map_or(1, |thread| thread.msg_count);
+    let payload = match matches.value_of("encryption_keypair") {
+        Some(path) => {
+            // The receiver decrypts with the key on our profile, so it has to be this keypair.
+            let secret_key = read_encryption_key(path)?;
+            let own_profile_pk = Profile::find_address(&sender_pk, program_id).0;
+            match config.account::<Profile>(&own_profile_pk)? {
+                Some(Profile {
+                    encryption_key: Some(key),
+                    ..
+                }) if key == *secret_key.public_key().as_bytes() => {}
+                _ => {
+                    return Err(
+                        "The encryption keypair is not the one on your profile, publish it with \
+                         set-profile first"
+                            .into(),
+                    )
+                }
+            }
+
+            let profile_pk = Profile::find_address(&receiver_pk, program_id).0;
+            let (encryption_key, key_version) = match config.account::<Profile>(&profile_pk)? {
+                Some(Profile {
+                    encryption_key: Some(key),
+                    encryption_key_version,
+                    ..
+                }) => (key, encryption_key_version),
+                _ => return Err("The receiver has not set an encryption key".into()),
+            };
+            let msg_pk = Message::find_address(msg_index, &sender_pk, &receiver_pk, program_id).0;
+            let data = encryption_box(&secret_key, &encryption_key)
+                .encrypt(nonce(&msg_pk), text.as_bytes())
+                .map_err(|_| "Can not encrypt the message")?;
+            MessagePayload::Encrypted(EncryptedMessage { key_version, data })
+        }
+        None => MessagePayload::Plain(text.as_bytes().to_vec()),
+    };
+    let instruction = instruction::send_message(
+        program_id,
+        &sender_pk,
+        &receiver_pk,
+        msg_index,
+        payload.kind(),
+        payload.pack()?,
+        matches.value_of("ttl").map(i64::from_str).transpose()?,
+        None,
+    )?;
+    config.send(&signer, instruction)
+}
+
+fn list_threads(config: &Config, matches: &ArgMatches) -> CliResult<()> {
+    let user_pk = config.user(matches)?;
+    for item in Threads::new(config, &user_pk, &config.program_id)? {
+        let (thread_pk, thread) = item?;
+        let (other_pk, state) = if thread.u1_pk == user_pk.to_bytes() {
+            (thread.u2_pk, thread.u1_state)
+        } else {
+            (thread.u1_pk, thread.u2_state)
+        };
+        println!(
+            "{} {} messages: {} since last read: {}",
+            thread_pk,
+            Pubkey::new(&other_pk),
+            thread.msg_count - 1,
+            (thread.msg_count - 1).saturating_sub(state.last_read),
+        );
+    }
+    Ok(())
+}
+
+fn read_messages(config: &Config, matches: &ArgMatches) -> CliResult<()> {
+    let user_pk = config.user(matches)?;
+    let other_pk = Pubkey::from_str(matches.value_of("other").unwrap())?;
+    let program_id = &config.program_id;
+    let thread_pk = Thread::find_address(&user_pk, &other_pk, program_id).0;
+    let thread = config
+        .account::<Thread>(&thread_pk)?
+        .ok_or("The thread does not exist")?;
+    let from = matches
+        .value_of("from")
+        .map(u32::from_str)
+        .transpose()?
+        .unwrap_or(1);
+    let to = matches
+        .value_of("to")
+        .map(u32::from_str)
+        .transpose()?
+        .unwrap_or(thread.msg_count - 1);
+    let profile = |pk: &Pubkey| config.account::<Profile>(&Profile::find_address(pk, program_id).0);
+    let user_profile = profile(&user_pk)?;
+    let other_profile = profile(&other_pk)?;
+    let encryption_box = match (matches.value_of("encryption_keypair"), &other_profile) {
+        (
+            Some(path),
+            Some(Profile {
+                encryption_key: Some(key),
+                ..
+            }),
+        ) => Some(encryption_box(&read_encryption_key(path)?, key)),
+        _ => None,
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let history = MessageHistory::new(config, &thread, program_id);
+    for entry in history.page(from..to.saturating_add(1), now)? {
+        let (sent, expired) = match entry.slot {
+            MessageSlot::Sent(sent) => (sent, false),
+            MessageSlot::Expired(sent) => (sent, true),
+            MessageSlot::Missing => {
+                println!("{} <closed>", entry.index);
+                continue;
+            }
+        };
+        let message = &sent.message;
+        let text = match message_payload(message) {
+            Ok(Some(MessagePayload::Text(text))) => text,
+            Ok(Some(MessagePayload::Plain(data))) => String::from_utf8_lossy(&data).into(),
+            Ok(Some(MessagePayload::Cta(cta))) => format!("{}: {}", cta.title, cta.body),
+            Ok(Some(MessagePayload::Encrypted(encrypted))) => {
+                // Only the current keys are known, so older messages can't be opened.
+                let receiver_profile = if sent.sender == user_pk {
+                    &other_profile
+                } else {
+                    &user_profile
+                };
+                let key_version = receiver_profile
+                    .as_ref()
+                    .map_or(0, |p| p.encryption_key_version);
+                if encrypted.key_version != key_version {
+                    format!(
+                        "<encrypted to key version {}, the receiver's key is at version {}>",
+                        encrypted.key_version, key_version
+                    )
+                } else {
+                    encryption_box
+                        .as_ref()
+                        .and_then(|b| b.decrypt(nonce(&sent.address), &encrypted.data[..]).ok())
+                        .map(|data| String::from_utf8_lossy(&data).into())
+                        .unwrap_or_else(|| "<encrypted>".into())
+                }
+            }
+            Ok(None) if message.kind == Message::RETRACTED_KIND => "<retracted>".into(),
+            Ok(None) | Err(_) => format!("<kind {}>", message.kind),
+        };
+        println!(
+            "{} {} {} {}{}",
+            entry.index,
+            sent.sender,
+            message.timestamp,
+            text,
+            if expired { " <expired>" } else { "" }
+        );
+    }
+    Ok(())
+}
+
+fn dump_accounts(config: &Config, matches: &ArgMatches) -> CliResult<()> {
+    let mut accounts = vec![];
+    for address in matches.values_of("address").unwrap() {
+        let address = Pubkey::from_str(address)?;
+        accounts.push(match config.account_data(&address)? {
+            Some(data) => json::account(&address, &data),
+            None => serde_json::json!({ "address": address.to_string(), "data": null }),
+        });
+    }
+    println!("{}", serde_json::to_string_pretty(&accounts)?);
+    Ok(())
+}
+
+fn main() -> CliResult<()> {
+    let user_arg = Arg::with_name("user")
+        .long("user")
+        .value_name("PUBKEY")
+        .takes_value(true)
+        .help("User to read for, defaults to the keypair");
+    let encryption_keypair_arg = Arg::with_name("encryption_keypair")
+        .long("encryption-keypair")
+        .value_name("PATH")
+        .takes_value(true)
+        .help("X25519 secret key, a JSON array of 32 bytes");
+
+    let matches = App::new("jabber")
+        .version(crate_version!())
+        .about("Send and read Jabber messages")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("url")
+                .long("url")
+                .value_name("URL")
+                .takes_value(true)
+                .default_value("http://localhost:8899")
+                .help("JSON RPC URL of the cluster"),
+        )
+        .arg(
+            Arg::with_name("keypair")
+                .long("keypair")
+                .value_name("PATH")
+                .takes_value(true)
+                .help("Keypair of the user, defaults to ~/.config/solana/id.json"),
+        )
+        .arg(
+            Arg::with_name("program_id")
+                .long("program-id")
+                .value_name("PUBKEY")
+                .takes_value(true)
+                .env("JABBER_PROGRAM_ID")
+                .help("Address of the Jabber program"),
+        )
+        .subcommand(
+            SubCommand::with_name("set-profile")
+                .about("Create or update the profile of the keypair")
+                .arg(Arg::with_name("name").long("name").takes_value(true))
+                .arg(Arg::with_name("bio").long("bio").takes_value(true))
+                .arg(
+                    Arg::with_name("lamports_per_message")
+                        .long("lamports-per-message")
+                        .value_name("LAMPORTS")
+                        .takes_value(true),
+                )
+                .arg(
+                    encryption_keypair_arg
+                        .clone()
+                        .help("Publish the public key of this X25519 secret key"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("send")
+                .about("Send a message, encrypted if an encryption keypair is given")
+                .arg(Arg::with_name("receiver").required(true).index(1))
+                .arg(Arg::with_name("message").required(true).index(2))
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .value_name("SECONDS")
+                        .takes_value(true)
+                        .help("Seconds after which anyone can close the message"),
+                )
+                .arg(encryption_keypair_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("threads")
+                .about("List the threads of a user, newest first")
+                .arg(user_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("messages")
+                .about("Read the messages of a thread")
+                .arg(Arg::with_name("other").required(true).index(1))
+                .arg(Arg::with_name("from").long("from").takes_value(true))
+                .arg(Arg::with_name("to").long("to").takes_value(true))
+                .arg(user_arg)
+                .arg(encryption_keypair_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Print accounts decoded as JSON")
+                .arg(
+                    Arg::with_name("address")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                ),
+        )
+        .get_matches();
+
+    let keypair_path = match matches.value_of("keypair") {
+        Some(path) => path.to_string(),
+        None => format!("{}/.config/solana/id.json", std::env::var("HOME")?),
+    };
+    let program_id = matches
+        .value_of("program_id")
+        .ok_or("--program-id is required")?;
+    let config = Config {
+        rpc: RpcClient::new(matches.value_of("url").unwrap().to_string()),
+        program_id: Pubkey::from_str(program_id)?,
+        keypair_path,
+    };
+
+    match matches.subcommand() {
+        ("set-profile", Some(matches)) => set_profile(&config, matches),
+        ("send", Some(matches)) => send_message(&config, matches),
+        ("threads", Some(matches)) => list_threads(&config, matches),
+        ("messages", Some(matches)) => read_messages(&config, matches),
+        ("dump", Some(matches)) => dump_accounts(&config, matches),
+        _ => unreachable!(),
+    }
+}
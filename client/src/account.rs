@@ -0,0 +1,128 @@
+use program::state::{
+    AccountKind, Channel, Group, Jabber, Message, MessagePayload, Profile, Serdes, Subscription,
+    Thread,
+};
+use solana_sdk::program_error::ProgramError;
+
+/// Any account owned by the Jabber program, decoded from its `AccountKind` header.
+#[derive(Debug, PartialEq)]
+pub enum JabberAccount {
+    Profile(Profile),
+    Thread(Thread),
+    Message(Message),
+    Jabber(Jabber),
+    /// Only the group header, use `Group::read_message` on the account data for its messages.
+    Group(Group),
+    Channel(Channel),
+    Subscription(Subscription),
+}
+
+impl JabberAccount {
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        match src.first() {
+            None => Err(ProgramError::UninitializedAccount),
+            Some(kind) if *kind == AccountKind::Uninitialized as u8 => {
+                Err(ProgramError::UninitializedAccount)
+            }
+            Some(kind) if *kind == AccountKind::Profile as u8 => {
+                Profile::unpack(src).map(JabberAccount::Profile)
+            }
+            Some(kind) if *kind == AccountKind::Thread as u8 => {
+                Thread::unpack(src).map(JabberAccount::Thread)
+            }
+            Some(kind) if *kind == AccountKind::Message as u8 => {
+                Message::unpack(src).map(JabberAccount::Message)
+            }
+            Some(kind) if *kind == AccountKind::Jabber as u8 => {
+                Jabber::unpack(src).map(JabberAccount::Jabber)
+            }
+            Some(kind) if *kind == AccountKind::Group as u8 => {
+                Group::unpack(src).map(JabberAccount::Group)
+            }
+            Some(kind) if *kind == AccountKind::Channel as u8 => {
+                Channel::unpack(src).map(JabberAccount::Channel)
+            }
+            Some(kind) if *kind == AccountKind::Subscription as u8 => {
+                Subscription::unpack(src).map(JabberAccount::Subscription)
+            }
+            Some(_) => Err(ProgramError::InvalidAccountData),
+        }
+    }
+
+    pub fn kind(&self) -> AccountKind {
+        match self {
+            JabberAccount::Profile(_) => AccountKind::Profile,
+            JabberAccount::Thread(_) => AccountKind::Thread,
+            JabberAccount::Message(_) => AccountKind::Message,
+            JabberAccount::Jabber(_) => AccountKind::Jabber,
+            JabberAccount::Group(_) => AccountKind::Group,
+            JabberAccount::Channel(_) => AccountKind::Channel,
+            JabberAccount::Subscription(_) => AccountKind::Subscription,
+        }
+    }
+}
+
+/// Decodes the typed payload of a message, `None` for custom and retracted messages.
+pub fn message_payload(message: &Message) -> Result<Option<MessagePayload>, ProgramError> {
+    if message.kind >= Message::CUSTOM_KIND_START || message.kind == Message::RETRACTED_KIND {
+        return Ok(None);
+    }
+    MessagePayload::unpack(message.kind, &message.msg).map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use program::state::ThreadState;
+
+    #[test]
+    fn test_unpack_account() {
+        let thread = Thread {
+            msg_count: 3,
+            prev_thread_u1_pk: None,
+            prev_thread_u2_pk: Some([1; 32]),
+            unregistered: false,
+            u1_pk: [1; 32],
+            u2_pk: [2; 32],
+            u1_ban: false,
+            u2_ban: false,
+            u1_state: ThreadState::default(),
+            u2_state: ThreadState::default(),
+        };
+        let mut data = vec![0; Thread::MIN_SPACE];
+        assert_eq!(
+            JabberAccount::unpack(&data),
+            Err(ProgramError::UninitializedAccount)
+        );
+        thread.pack(&mut data).unwrap();
+        let account = JabberAccount::unpack(&data).unwrap();
+        assert_eq!(account.kind(), AccountKind::Thread);
+        assert_eq!(account, JabberAccount::Thread(thread));
+
+        data[0] = 42;
+        assert_eq!(
+            JabberAccount::unpack(&data),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_message_payload() {
+        let mut message = Message {
+            kind: Message::TEXT_KIND,
+            msg: MessagePayload::Text("Hey".into()).pack().unwrap(),
+            timestamp: 0,
+            ttl: None,
+            edited: None,
+            prev_msg: None,
+            payer: None,
+            meta: vec![],
+        };
+        assert_eq!(
+            message_payload(&message),
+            Ok(Some(MessagePayload::Text("Hey".into())))
+        );
+        message.kind = Message::CUSTOM_KIND_START;
+        assert_eq!(message_payload(&message), Ok(None));
+    }
+}
@@ -0,0 +1,160 @@
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+use std::{
+    io::{BufRead, Read},
+    str::FromStr,
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum FeedError {
+    #[error("Can not read the feed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid JSON on line {0}: {1}")]
+    Json(usize, serde_json::Error),
+    #[error("Invalid account update on line {0}")]
+    InvalidUpdate(usize),
+}
+
+/// New data of a program account at `slot`, `None` once the account is closed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccountUpdate {
+    pub slot: u64,
+    pub address: Pubkey,
+    pub data: Option<Vec<u8>>,
+}
+
+impl AccountUpdate {
+    /// The update as a feed line, `{"slot": 1, "pubkey": "<base58>", "data": "<base64>"}`.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "slot": self.slot,
+            "pubkey": self.address.to_string(),
+            "data": self.data.as_ref().map(base64::encode),
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let address = Pubkey::from_str(value.get("pubkey")?.as_str()?).ok()?;
+        let data = match value.get("data") {
+            None | Some(Value::Null) => None,
+            Some(data) => Some(base64::decode(data.as_str()?).ok()?),
+        };
+        Some(AccountUpdate {
+            slot: value.get("slot")?.as_u64()?,
+            address,
+            data,
+        })
+    }
+
+    /// An item of a `getProgramAccounts` result with base64 encoding.
+    fn from_program_account(value: &Value, slot: u64) -> Option<Self> {
+        let address = Pubkey::from_str(value.get("pubkey")?.as_str()?).ok()?;
+        let data = match value.get("account")?.get("data")? {
+            Value::Array(data) if data.get(1)?.as_str()? == "base64" => data.first()?.as_str()?,
+            _ => return None,
+        };
+        Some(AccountUpdate {
+            slot,
+            address,
+            data: Some(base64::decode(data).ok()?),
+        })
+    }
+}
+
+/// Reads a feed of account updates, one JSON object per line in the order they were
+/// recorded. Empty lines are skipped.
+pub fn read_feed<R: BufRead>(reader: R) -> impl Iterator<Item = Result<AccountUpdate, FeedError>> {
+    reader
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| match line {
+            Ok(line) if line.trim().is_empty() => None,
+            Ok(line) => Some(parse_line(i + 1, &line)),
+            Err(err) => Some(Err(err.into())),
+        })
+}
+
+fn parse_line(line_number: usize, line: &str) -> Result<AccountUpdate, FeedError> {
+    let value: Value =
+        serde_json::from_str(line).map_err(|err| FeedError::Json(line_number, err))?;
+    AccountUpdate::from_json(&value).ok_or(FeedError::InvalidUpdate(line_number))
+}
+
+/// Reads a snapshot of all program accounts, the saved response or result of a
+/// `getProgramAccounts` request with base64 encoding. All accounts are reported at `slot`.
+pub fn read_snapshot<R: Read>(reader: R, slot: u64) -> Result<Vec<AccountUpdate>, FeedError> {
+    let value: Value = serde_json::from_reader(reader).map_err(|err| FeedError::Json(1, err))?;
+    let accounts = match value.get("result").unwrap_or(&value) {
+        Value::Array(accounts) => accounts,
+        _ => return Err(FeedError::InvalidUpdate(1)),
+    };
+    accounts
+        .iter()
+        .enumerate()
+        .map(|(i, account)| {
+            AccountUpdate::from_program_account(account, slot).ok_or(FeedError::InvalidUpdate(i))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_feed() {
+        let updates = vec![
+            AccountUpdate {
+                slot: 3,
+                address: Pubkey::new(&[1; 32]),
+                data: Some(vec![1, 2, 3]),
+            },
+            AccountUpdate {
+                slot: 4,
+                address: Pubkey::new(&[1; 32]),
+                data: None,
+            },
+        ];
+        let feed = updates
+            .iter()
+            .map(|update| update.to_json().to_string())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let read: Vec<_> = read_feed(feed.as_bytes()).map(Result::unwrap).collect();
+        assert_eq!(read, updates);
+
+        let mut errors = read_feed(&b"{\"slot\": 1}\nnot json"[..]);
+        assert!(matches!(
+            errors.next(),
+            Some(Err(FeedError::InvalidUpdate(1)))
+        ));
+        assert!(matches!(errors.next(), Some(Err(FeedError::Json(2, _)))));
+    }
+
+    #[test]
+    fn test_read_snapshot() {
+        let address = Pubkey::new(&[2; 32]);
+        let snapshot = json!({
+            "jsonrpc": "2.0",
+            "result": [{
+                "pubkey": address.to_string(),
+                "account": {
+                    "data": [base64::encode([4, 5]), "base64"],
+                    "lamports": 10,
+                },
+            }],
+            "id": 1,
+        })
+        .to_string();
+        assert_eq!(
+            read_snapshot(snapshot.as_bytes(), 7).unwrap(),
+            vec![AccountUpdate {
+                slot: 7,
+                address,
+                data: Some(vec![4, 5]),
+            }]
+        );
+        assert!(read_snapshot(&b"{}"[..], 7).is_err());
+    }
+}
@@ -0,0 +1,248 @@
+use crate::source::{AccountSource, SourceError};
+use program::state::{Message, Serdes, Thread};
+use solana_sdk::{clock::UnixTimestamp, program_error::ProgramError, pubkey::Pubkey};
+use std::ops::Range;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum HistoryError {
+    #[error("Account source failed: {0}")]
+    Source(#[from] SourceError),
+    #[error("Account source returned {0} accounts, expected {1}")]
+    SourceLength(usize, usize),
+    #[error("Message {0} can not be decoded: {1}")]
+    InvalidMessage(Pubkey, ProgramError),
+    /// Both users have a message at the same index.
+    #[error("Message index {0} is used by both users")]
+    DuplicateIndex(u32),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SentMessage {
+    pub address: Pubkey,
+    pub sender: Pubkey,
+    pub message: Message,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MessageSlot {
+    Sent(SentMessage),
+    /// The message is past its `ttl`, anyone can close it.
+    Expired(SentMessage),
+    /// Neither user has a message at the index, it has been closed.
+    Missing,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct HistoryEntry {
+    pub index: u32,
+    pub slot: MessageSlot,
+}
+
+/// Reads the messages of a thread by index. Each index has one candidate address per sender,
+/// both are fetched in batches from the account source.
+pub struct MessageHistory<'a, S> {
+    source: &'a S,
+    program_id: Pubkey,
+    u1_pk: Pubkey,
+    u2_pk: Pubkey,
+    msg_count: u32,
+    batch_size: usize,
+}
+
+impl<'a, S: AccountSource> MessageHistory<'a, S> {
+    /// Indexes fetched per request by default, two accounts each.
+    pub const BATCH_SIZE: usize = 50;
+
+    pub fn new(source: &'a S, thread: &Thread, program_id: &Pubkey) -> Self {
+        MessageHistory {
+            source,
+            program_id: *program_id,
+            u1_pk: Pubkey::new(&thread.u1_pk),
+            u2_pk: Pubkey::new(&thread.u2_pk),
+            msg_count: thread.msg_count,
+            batch_size: Self::BATCH_SIZE,
+        }
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Messages in `range`, in order. The range is limited to the indexes the thread has used,
+    /// from 1 up to `msg_count`.
+    pub fn page(
+        &self,
+        range: Range<u32>,
+        now: UnixTimestamp,
+    ) -> Result<Vec<HistoryEntry>, HistoryError> {
+        let indexes: Vec<u32> = (range.start.max(1)..range.end.min(self.msg_count)).collect();
+        let mut entries = Vec::with_capacity(indexes.len());
+        for batch in indexes.chunks(self.batch_size) {
+            let senders = [(&self.u1_pk, &self.u2_pk), (&self.u2_pk, &self.u1_pk)];
+            let addresses: Vec<Pubkey> = batch
+                .iter()
+                .flat_map(|index| {
+                    senders.iter().map(move |(from_pk, to_pk)| {
+                        Message::find_address(*index, from_pk, to_pk, &self.program_id).0
+                    })
+                })
+                .collect();
+            let accounts = self.source.get_multiple_account_data(&addresses)?;
+            if accounts.len() != addresses.len() {
+                return Err(HistoryError::SourceLength(accounts.len(), addresses.len()));
+            }
+
+            for (i, index) in batch.iter().enumerate() {
+                let mut slot = MessageSlot::Missing;
+                for (j, (from_pk, _)) in senders.iter().enumerate() {
+                    let address = addresses[2 * i + j];
+                    let message = match &accounts[2 * i + j] {
+                        Some(data) if Message::is_initialized(data) => Message::unpack(data)
+                            .map_err(|err| HistoryError::InvalidMessage(address, err))?,
+                        _ => continue,
+                    };
+                    if slot != MessageSlot::Missing {
+                        return Err(HistoryError::DuplicateIndex(*index));
+                    }
+                    let sent = SentMessage {
+                        address,
+                        sender: **from_pk,
+                        message,
+                    };
+                    slot = if sent.message.is_expired(now) {
+                        MessageSlot::Expired(sent)
+                    } else {
+                        MessageSlot::Sent(sent)
+                    };
+                }
+                entries.push(HistoryEntry {
+                    index: *index,
+                    slot,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The last `count` messages, in order.
+    pub fn latest(
+        &self,
+        count: u32,
+        now: UnixTimestamp,
+    ) -> Result<Vec<HistoryEntry>, HistoryError> {
+        self.page(self.msg_count.saturating_sub(count)..self.msg_count, now)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use program::state::ThreadState;
+    use std::{cell::RefCell, collections::HashMap};
+
+    fn rand_pk() -> Pubkey {
+        Pubkey::new(&rand::random::<[u8; 32]>())
+    }
+
+    /// Counts the requests made to the accounts.
+    struct CountingSource {
+        accounts: HashMap<Pubkey, Vec<u8>>,
+        requests: RefCell<Vec<usize>>,
+    }
+
+    impl AccountSource for CountingSource {
+        fn get_account_data(&self, address: &Pubkey) -> Result<Option<Vec<u8>>, SourceError> {
+            self.accounts.get_account_data(address)
+        }
+
+        fn get_multiple_account_data(
+            &self,
+            addresses: &[Pubkey],
+        ) -> Result<Vec<Option<Vec<u8>>>, SourceError> {
+            self.requests.borrow_mut().push(addresses.len());
+            self.accounts.get_multiple_account_data(addresses)
+        }
+    }
+
+    fn message(timestamp: UnixTimestamp, ttl: Option<UnixTimestamp>) -> Vec<u8> {
+        let message = Message {
+            kind: Message::CUSTOM_KIND_START,
+            msg: b"Hey".to_vec(),
+            timestamp,
+            ttl,
+            edited: None,
+            prev_msg: None,
+            payer: None,
+            meta: vec![],
+        };
+        let mut data = vec![0; message.packed_len().unwrap()];
+        message.pack(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_message_history() {
+        let program_id = rand_pk();
+        let (u1_pk, u2_pk) = (rand_pk(), rand_pk());
+        let thread = Thread {
+            msg_count: 5,
+            prev_thread_u1_pk: None,
+            prev_thread_u2_pk: None,
+            unregistered: false,
+            u1_pk: u1_pk.to_bytes(),
+            u2_pk: u2_pk.to_bytes(),
+            u1_ban: false,
+            u2_ban: false,
+            u1_state: ThreadState::default(),
+            u2_state: ThreadState::default(),
+        };
+        let mut accounts = HashMap::new();
+        for (index, from_pk, to_pk, ttl) in [
+            (1, &u1_pk, &u2_pk, None),
+            (2, &u2_pk, &u1_pk, Some(10)),
+            (4, &u2_pk, &u1_pk, None),
+        ]
+        .iter()
+        {
+            accounts.insert(
+                Message::find_address(*index, from_pk, to_pk, &program_id).0,
+                message(100, *ttl),
+            );
+        }
+        let source = CountingSource {
+            accounts,
+            requests: RefCell::new(vec![]),
+        };
+
+        let history = MessageHistory::new(&source, &thread, &program_id).with_batch_size(3);
+        let entries = history.page(0..10, 105).unwrap();
+        assert_eq!(*source.requests.borrow(), vec![6, 2]);
+        let slots: Vec<_> = entries
+            .iter()
+            .map(|entry| match &entry.slot {
+                MessageSlot::Sent(sent) => (entry.index, "sent", Some(sent.sender)),
+                MessageSlot::Expired(sent) => (entry.index, "expired", Some(sent.sender)),
+                MessageSlot::Missing => (entry.index, "missing", None),
+            })
+            .collect();
+        assert_eq!(
+            slots,
+            vec![
+                (1, "sent", Some(u1_pk)),
+                (2, "sent", Some(u2_pk)),
+                (3, "missing", None),
+                (4, "sent", Some(u2_pk)),
+            ]
+        );
+
+        let entries = history.latest(3, 110).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].index, 2);
+        match &entries[0].slot {
+            MessageSlot::Expired(sent) => assert_eq!(sent.message.ttl, Some(10)),
+            slot => panic!("Unexpected {:?}", slot),
+        }
+    }
+}
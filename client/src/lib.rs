@@ -0,0 +1,20 @@
+//! Rust client for the Jabber program.
+//!
+//! Re-exports the program's state types, address derivation and instruction builders, and
+//! decodes raw account data into the matching state type. Accounts are read through an
+//! `AccountSource`, so the same code works against RPC nodes, snapshots and fixtures.
+//! Off-chain services replay account updates from feed and snapshot files.
+
+pub use program::{error, instruction, state};
+
+mod account;
+mod feed;
+mod history;
+mod source;
+mod thread;
+
+pub use account::{message_payload, JabberAccount};
+pub use feed::{read_feed, read_snapshot, AccountUpdate, FeedError};
+pub use history::{HistoryEntry, HistoryError, MessageHistory, MessageSlot, SentMessage};
+pub use source::{AccountSource, SourceError};
+pub use thread::{ThreadListError, Threads};
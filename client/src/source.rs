@@ -0,0 +1,28 @@
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashMap;
+
+pub type SourceError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Where account data is read from, an RPC node, a snapshot or test fixtures.
+pub trait AccountSource {
+    /// Data of the account at `address`, `None` if it does not exist.
+    fn get_account_data(&self, address: &Pubkey) -> Result<Option<Vec<u8>>, SourceError>;
+
+    /// Data of several accounts, in the order of `addresses`. Override this when the source
+    /// can fetch accounts in one request.
+    fn get_multiple_account_data(
+        &self,
+        addresses: &[Pubkey],
+    ) -> Result<Vec<Option<Vec<u8>>>, SourceError> {
+        addresses
+            .iter()
+            .map(|address| self.get_account_data(address))
+            .collect()
+    }
+}
+
+impl AccountSource for HashMap<Pubkey, Vec<u8>> {
+    fn get_account_data(&self, address: &Pubkey) -> Result<Option<Vec<u8>>, SourceError> {
+        Ok(self.get(address).cloned())
+    }
+}
@@ -0,0 +1,228 @@
+use crate::source::{AccountSource, SourceError};
+use program::state::{Profile, PublicKey, Serdes, Thread};
+use solana_sdk::{program_error::ProgramError, pubkey::Pubkey};
+use std::collections::HashSet;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ThreadListError {
+    #[error("Account source failed: {0}")]
+    Source(#[from] SourceError),
+    #[error("Profile {0} can not be decoded: {1}")]
+    InvalidProfile(Pubkey, ProgramError),
+    /// The previous thread points to an account that does not exist.
+    #[error("Thread {0} does not exist")]
+    MissingThread(Pubkey),
+    #[error("Thread {0} can not be decoded: {1}")]
+    InvalidThread(Pubkey, ProgramError),
+    /// The account is not the thread of the user and the other participant it lists.
+    #[error("Thread {0} is not a thread of the user")]
+    NotUserThread(Pubkey),
+    #[error("Thread {0} is listed twice")]
+    Cycle(Pubkey),
+}
+
+/// Iterates the threads of a user from the newest, following `prev_thread_u1_pk` or
+/// `prev_thread_u2_pk` depending on the user's side of each thread.
+///
+/// Stops after the first error.
+pub struct Threads<'a, S> {
+    source: &'a S,
+    program_id: Pubkey,
+    user_pk: Pubkey,
+    next: Option<PublicKey>,
+    seen: HashSet<Pubkey>,
+}
+
+impl<'a, S: AccountSource> Threads<'a, S> {
+    /// Threads of `user_pk`, starting at the `thread_tail_pk` of their profile. Users without
+    /// a profile have no threads.
+    pub fn new(
+        source: &'a S,
+        user_pk: &Pubkey,
+        program_id: &Pubkey,
+    ) -> Result<Self, ThreadListError> {
+        let profile_pk = Profile::find_address(user_pk, program_id).0;
+        let tail = match source.get_account_data(&profile_pk)? {
+            Some(data) if Profile::is_initialized(&data) => {
+                Profile::unpack(&data)
+                    .map_err(|err| ThreadListError::InvalidProfile(profile_pk, err))?
+                    .thread_tail_pk
+            }
+            _ => None,
+        };
+        Ok(Threads::from_tail(source, user_pk, tail, program_id))
+    }
+
+    /// Threads of `user_pk` starting at `tail`.
+    pub fn from_tail(
+        source: &'a S,
+        user_pk: &Pubkey,
+        tail: Option<PublicKey>,
+        program_id: &Pubkey,
+    ) -> Self {
+        Threads {
+            source,
+            program_id: *program_id,
+            user_pk: *user_pk,
+            next: tail,
+            seen: HashSet::new(),
+        }
+    }
+
+    fn next_thread(&mut self, thread_pk: Pubkey) -> Result<(Pubkey, Thread), ThreadListError> {
+        if !self.seen.insert(thread_pk) {
+            return Err(ThreadListError::Cycle(thread_pk));
+        }
+        let data = self
+            .source
+            .get_account_data(&thread_pk)?
+            .filter(|data| Thread::is_initialized(data))
+            .ok_or(ThreadListError::MissingThread(thread_pk))?;
+        let thread =
+            Thread::unpack(&data).map_err(|err| ThreadListError::InvalidThread(thread_pk, err))?;
+
+        let user = self.user_pk.to_bytes();
+        let prev = if thread.u1_pk == user {
+            thread.prev_thread_u1_pk
+        } else if thread.u2_pk == user {
+            thread.prev_thread_u2_pk
+        } else {
+            return Err(ThreadListError::NotUserThread(thread_pk));
+        };
+        let (u1_pk, u2_pk) = (Pubkey::new(&thread.u1_pk), Pubkey::new(&thread.u2_pk));
+        if Thread::find_address(&u1_pk, &u2_pk, &self.program_id).0 != thread_pk {
+            return Err(ThreadListError::NotUserThread(thread_pk));
+        }
+        self.next = prev;
+        Ok((thread_pk, thread))
+    }
+}
+
+impl<'a, S: AccountSource> Iterator for Threads<'a, S> {
+    type Item = Result<(Pubkey, Thread), ThreadListError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let thread_pk = Pubkey::new(&self.next.take()?);
+        Some(self.next_thread(thread_pk))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use program::state::ThreadState;
+    use std::collections::HashMap;
+
+    fn rand_pk() -> Pubkey {
+        Pubkey::new(&rand::random::<[u8; 32]>())
+    }
+
+    fn thread(u1_pk: &Pubkey, u2_pk: &Pubkey) -> Thread {
+        let (u1_pk, u2_pk) = Thread::ordered_pks(u1_pk, u2_pk);
+        Thread {
+            msg_count: 1,
+            prev_thread_u1_pk: None,
+            prev_thread_u2_pk: None,
+            unregistered: false,
+            u1_pk: u1_pk.to_bytes(),
+            u2_pk: u2_pk.to_bytes(),
+            u1_ban: false,
+            u2_ban: false,
+            u1_state: ThreadState::default(),
+            u2_state: ThreadState::default(),
+        }
+    }
+
+    fn insert<T: Serdes>(accounts: &mut HashMap<Pubkey, Vec<u8>>, pk: Pubkey, account: &T) {
+        let mut data = vec![0; account.packed_len().unwrap()];
+        account.pack(&mut data).unwrap();
+        accounts.insert(pk, data);
+    }
+
+    /// Sets the user's prev pointer of `thread`.
+    fn set_prev(thread: &mut Thread, user_pk: &Pubkey, prev: Option<Pubkey>) {
+        let prev = prev.map(|pk| pk.to_bytes());
+        if thread.u1_pk == user_pk.to_bytes() {
+            thread.prev_thread_u1_pk = prev;
+        } else {
+            thread.prev_thread_u2_pk = prev;
+        }
+    }
+
+    #[test]
+    fn test_threads() {
+        let program_id = rand_pk();
+        let user_pk = rand_pk();
+        let others: Vec<_> = (0..3).map(|_| rand_pk()).collect();
+        let thread_pks: Vec<_> = others
+            .iter()
+            .map(|pk| Thread::find_address(&user_pk, pk, &program_id).0)
+            .collect();
+        let mut accounts = HashMap::new();
+
+        // No profile, no threads.
+        let threads = Threads::new(&accounts, &user_pk, &program_id).unwrap();
+        assert_eq!(threads.count(), 0);
+
+        // Threads are linked newest first, the other users have their own lists.
+        let mut threads: Vec<_> = others.iter().map(|pk| thread(&user_pk, pk)).collect();
+        set_prev(&mut threads[2], &user_pk, Some(thread_pks[1]));
+        set_prev(&mut threads[2], &others[2], Some(rand_pk()));
+        set_prev(&mut threads[1], &user_pk, Some(thread_pks[0]));
+        for (pk, thread) in thread_pks.iter().zip(threads.iter()) {
+            insert(&mut accounts, *pk, thread);
+        }
+        let profile = Profile {
+            thread_tail_pk: Some(thread_pks[2].to_bytes()),
+            ..Profile::default()
+        };
+        insert(
+            &mut accounts,
+            Profile::find_address(&user_pk, &program_id).0,
+            &profile,
+        );
+        let listed: Vec<_> = Threads::new(&accounts, &user_pk, &program_id)
+            .unwrap()
+            .map(|item| item.unwrap().0)
+            .collect();
+        assert_eq!(listed, vec![thread_pks[2], thread_pks[1], thread_pks[0]]);
+
+        // Cycle.
+        set_prev(&mut threads[0], &user_pk, Some(thread_pks[2]));
+        insert(&mut accounts, thread_pks[0], &threads[0]);
+        let listed: Vec<_> = Threads::new(&accounts, &user_pk, &program_id)
+            .unwrap()
+            .collect();
+        assert_eq!(listed.len(), 4);
+        match &listed[3] {
+            Err(ThreadListError::Cycle(pk)) => assert_eq!(*pk, thread_pks[2]),
+            item => panic!("Unexpected {:?}", item),
+        }
+
+        // Broken link.
+        accounts.remove(&thread_pks[0]);
+        let mut listed = Threads::new(&accounts, &user_pk, &program_id).unwrap();
+        listed.next().unwrap().unwrap();
+        listed.next().unwrap().unwrap();
+        match listed.next() {
+            Some(Err(ThreadListError::MissingThread(pk))) => assert_eq!(pk, thread_pks[0]),
+            item => panic!("Unexpected {:?}", item),
+        }
+        assert!(listed.next().is_none());
+
+        // Someone else's thread.
+        let stranger_pk = Thread::find_address(&others[0], &others[1], &program_id).0;
+        insert(&mut accounts, stranger_pk, &thread(&others[0], &others[1]));
+        let mut listed = Threads::from_tail(
+            &accounts,
+            &user_pk,
+            Some(stranger_pk.to_bytes()),
+            &program_id,
+        );
+        match listed.next() {
+            Some(Err(ThreadListError::NotUserThread(pk))) => assert_eq!(pk, stranger_pk),
+            item => panic!("Unexpected {:?}", item),
+        }
+    }
+}
@@ -0,0 +1,560 @@
+use jabber_client::{
+    message_payload,
+    state::{Message, MessagePayload, Profile, Thread},
+    AccountUpdate, FeedError, JabberAccount,
+};
+use log::warn;
+use rusqlite::{params, types::Type, Connection, Row};
+use solana_sdk::pubkey::Pubkey;
+use std::str::FromStr;
+use thiserror::Error;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    profile TEXT PRIMARY KEY,
+    -- Known once the user shows up in a thread, profiles do not store their user.
+    user TEXT UNIQUE,
+    registered INTEGER NOT NULL DEFAULT 0,
+    name TEXT,
+    bio TEXT,
+    lamports_per_message INTEGER NOT NULL DEFAULT 0,
+    encryption_key TEXT,
+    encryption_key_version INTEGER NOT NULL DEFAULT 0,
+    slot INTEGER NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS threads (
+    address TEXT PRIMARY KEY,
+    u1 TEXT NOT NULL,
+    u2 TEXT NOT NULL,
+    msg_count INTEGER NOT NULL,
+    prev_thread_u1 TEXT,
+    prev_thread_u2 TEXT,
+    u1_ban INTEGER NOT NULL,
+    u2_ban INTEGER NOT NULL,
+    u1_last_read INTEGER NOT NULL,
+    u2_last_read INTEGER NOT NULL,
+    u1_muted INTEGER NOT NULL,
+    u2_muted INTEGER NOT NULL,
+    u1_archived INTEGER NOT NULL,
+    u2_archived INTEGER NOT NULL,
+    slot INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS threads_u1 ON threads (u1);
+CREATE INDEX IF NOT EXISTS threads_u2 ON threads (u2);
+-- Message addresses derived from the threads, messages do not store their sender.
+CREATE TABLE IF NOT EXISTS message_keys (
+    address TEXT PRIMARY KEY,
+    thread TEXT NOT NULL,
+    idx INTEGER NOT NULL,
+    sender TEXT NOT NULL,
+    receiver TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS message_keys_thread ON message_keys (thread, idx);
+CREATE TABLE IF NOT EXISTS messages (
+    address TEXT PRIMARY KEY,
+    kind INTEGER NOT NULL,
+    -- Searchable text of the message, NULL for encrypted and custom messages.
+    body TEXT,
+    data BLOB NOT NULL,
+    timestamp INTEGER NOT NULL,
+    ttl INTEGER,
+    edited INTEGER,
+    prev_msg TEXT,
+    slot INTEGER NOT NULL
+);
+";
+
+/// Threads of the user with the other participant's side resolved.
+const USER_THREADS: &str = "
+WITH mine AS (
+    SELECT
+        address,
+        CASE WHEN u1 = ?1 THEN u2 ELSE u1 END AS other,
+        msg_count,
+        CASE WHEN u1 = ?1 THEN u1_last_read ELSE u2_last_read END AS last_read,
+        CASE WHEN u1 = ?1 THEN u1_muted ELSE u2_muted END AS muted,
+        CASE WHEN u1 = ?1 THEN u1_archived ELSE u2_archived END AS archived
+    FROM threads
+    WHERE u1 = ?1 OR u2 = ?1
+)";
+
+#[derive(Debug, Error)]
+pub enum IndexError {
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Feed(#[from] FeedError),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InboxThread {
+    pub thread: Pubkey,
+    pub other: Pubkey,
+    pub other_name: Option<String>,
+    /// Number of messages sent on the thread, including closed ones.
+    pub msg_count: u32,
+    /// Messages from the other user after the user's last read index.
+    pub unread: u32,
+    pub last_timestamp: Option<i64>,
+    pub muted: bool,
+    pub archived: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SearchResult {
+    pub thread: Pubkey,
+    pub index: u32,
+    pub sender: Pubkey,
+    pub timestamp: i64,
+    pub body: String,
+}
+
+pub struct Index {
+    conn: Connection,
+    program_id: Pubkey,
+}
+
+impl Index {
+    pub fn open(path: &str, program_id: &Pubkey) -> Result<Self, IndexError> {
+        Index::new(Connection::open(path)?, program_id)
+    }
+
+    pub fn open_in_memory(program_id: &Pubkey) -> Result<Self, IndexError> {
+        Index::new(Connection::open_in_memory()?, program_id)
+    }
+
+    fn new(conn: Connection, program_id: &Pubkey) -> Result<Self, IndexError> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Index {
+            conn,
+            program_id: *program_id,
+        })
+    }
+
+    /// Applies the updates in one transaction, returns how many were applied. Updates older
+    /// than the indexed row are ignored, so feeds can be replayed.
+    pub fn apply_all<I>(&mut self, updates: I) -> Result<usize, IndexError>
+    where
+        I: IntoIterator<Item = Result<AccountUpdate, FeedError>>,
+    {
+        let tx = self.conn.transaction()?;
+        let mut count = 0;
+        for update in updates {
+            apply(&tx, &self.program_id, &update?)?;
+            count += 1;
+        }
+        tx.commit()?;
+        Ok(count)
+    }
+
+    pub fn apply(&mut self, update: &AccountUpdate) -> Result<(), IndexError> {
+        apply(&self.conn, &self.program_id, update)
+    }
+
+    /// Threads of `user`, the most recent message first.
+    pub fn inbox(&self, user: &Pubkey) -> Result<Vec<InboxThread>, IndexError> {
+        let mut stmt = self.conn.prepare(&format!(
+            "{}
+            SELECT
+                mine.address,
+                mine.other,
+                (SELECT name FROM users WHERE user = mine.other),
+                mine.msg_count - 1,
+                (SELECT COUNT(*) FROM message_keys k JOIN messages m ON m.address = k.address
+                    WHERE k.thread = mine.address AND k.sender != ?1 AND k.idx > mine.last_read),
+                (SELECT MAX(m.timestamp) FROM message_keys k JOIN messages m ON m.address = k.address
+                    WHERE k.thread = mine.address),
+                mine.muted,
+                mine.archived
+            FROM mine
+            ORDER BY 6 DESC",
+            USER_THREADS
+        ))?;
+        let threads = stmt
+            .query_map(params![user.to_string()], |row| {
+                Ok(InboxThread {
+                    thread: pubkey(row, 0)?,
+                    other: pubkey(row, 1)?,
+                    other_name: row.get(2)?,
+                    msg_count: row.get(3)?,
+                    unread: row.get(4)?,
+                    last_timestamp: row.get(5)?,
+                    muted: row.get(6)?,
+                    archived: row.get(7)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(threads)
+    }
+
+    /// Unread messages of `user` over threads that are not muted or archived.
+    pub fn unread_count(&self, user: &Pubkey) -> Result<u32, IndexError> {
+        let count = self.conn.query_row(
+            &format!(
+                "{}
+                SELECT COUNT(*) FROM mine
+                JOIN message_keys k ON k.thread = mine.address
+                JOIN messages m ON m.address = k.address
+                WHERE k.sender != ?1 AND k.idx > mine.last_read
+                    AND NOT mine.muted AND NOT mine.archived",
+                USER_THREADS
+            ),
+            params![user.to_string()],
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+
+    /// Messages sent or received by `user` containing `text`, the newest first.
+    pub fn search(
+        &self,
+        user: &Pubkey,
+        text: &str,
+        limit: u32,
+    ) -> Result<Vec<SearchResult>, IndexError> {
+        let pattern = format!(
+            "%{}%",
+            text.replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+        let mut stmt = self.conn.prepare(
+            "SELECT k.thread, k.idx, k.sender, m.timestamp, m.body
+            FROM messages m JOIN message_keys k ON k.address = m.address
+            WHERE (k.sender = ?1 OR k.receiver = ?1) AND m.body LIKE ?2 ESCAPE '\\'
+            ORDER BY m.timestamp DESC
+            LIMIT ?3",
+        )?;
+        let results = stmt
+            .query_map(params![user.to_string(), pattern, limit], |row| {
+                Ok(SearchResult {
+                    thread: pubkey(row, 0)?,
+                    index: row.get(1)?,
+                    sender: pubkey(row, 2)?,
+                    timestamp: row.get(3)?,
+                    body: row.get(4)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(results)
+    }
+}
+
+fn apply(conn: &Connection, program_id: &Pubkey, update: &AccountUpdate) -> Result<(), IndexError> {
+    let address = update.address.to_string();
+    let slot = update.slot as i64;
+    let data = match &update.data {
+        Some(data) => data,
+        None => {
+            conn.execute(
+                "DELETE FROM threads WHERE address = ?1 AND slot <= ?2",
+                params![address, slot],
+            )?;
+            conn.execute(
+                "DELETE FROM messages WHERE address = ?1 AND slot <= ?2",
+                params![address, slot],
+            )?;
+            return Ok(());
+        }
+    };
+    match JabberAccount::unpack(data) {
+        Ok(JabberAccount::Profile(profile)) => apply_profile(conn, &address, slot, &profile)?,
+        Ok(JabberAccount::Thread(thread)) => {
+            apply_thread(conn, program_id, &address, slot, &thread)?
+        }
+        Ok(JabberAccount::Message(message)) => apply_message(conn, &address, slot, &message)?,
+        // Groups and channels are not indexed.
+        Ok(_) => {}
+        Err(err) => warn!("Skipping account {} at slot {}: {}", address, slot, err),
+    }
+    Ok(())
+}
+
+fn apply_profile(
+    conn: &Connection,
+    address: &str,
+    slot: i64,
+    profile: &Profile,
+) -> Result<(), IndexError> {
+    conn.execute(
+        "INSERT INTO users (profile, registered, name, bio, lamports_per_message,
+            encryption_key, encryption_key_version, slot)
+        VALUES (?1, 1, ?2, ?3, ?4, ?5, ?6, ?7)
+        ON CONFLICT (profile) DO UPDATE SET
+            registered = 1,
+            name = excluded.name,
+            bio = excluded.bio,
+            lamports_per_message = excluded.lamports_per_message,
+            encryption_key = excluded.encryption_key,
+            encryption_key_version = excluded.encryption_key_version,
+            slot = excluded.slot
+        WHERE excluded.slot >= users.slot",
+        params![
+            address,
+            profile.name,
+            profile.bio,
+            profile.lamports_per_message as i64,
+            profile
+                .encryption_key
+                .map(|key| Pubkey::new(&key).to_string()),
+            profile.encryption_key_version,
+            slot,
+        ],
+    )?;
+    Ok(())
+}
+
+fn apply_thread(
+    conn: &Connection,
+    program_id: &Pubkey,
+    address: &str,
+    slot: i64,
+    thread: &Thread,
+) -> Result<(), IndexError> {
+    let u1_pk = Pubkey::new(&thread.u1_pk);
+    let u2_pk = Pubkey::new(&thread.u2_pk);
+    let prev_pk = |pk: &Option<[u8; 32]>| pk.map(|pk| Pubkey::new(&pk).to_string());
+    conn.execute(
+        "INSERT INTO threads (address, u1, u2, msg_count, prev_thread_u1, prev_thread_u2,
+            u1_ban, u2_ban, u1_last_read, u2_last_read, u1_muted, u2_muted,
+            u1_archived, u2_archived, slot)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)
+        ON CONFLICT (address) DO UPDATE SET
+            msg_count = excluded.msg_count,
+            prev_thread_u1 = excluded.prev_thread_u1,
+            prev_thread_u2 = excluded.prev_thread_u2,
+            u1_ban = excluded.u1_ban,
+            u2_ban = excluded.u2_ban,
+            u1_last_read = excluded.u1_last_read,
+            u2_last_read = excluded.u2_last_read,
+            u1_muted = excluded.u1_muted,
+            u2_muted = excluded.u2_muted,
+            u1_archived = excluded.u1_archived,
+            u2_archived = excluded.u2_archived,
+            slot = excluded.slot
+        WHERE excluded.slot >= threads.slot",
+        params![
+            address,
+            u1_pk.to_string(),
+            u2_pk.to_string(),
+            thread.msg_count,
+            prev_pk(&thread.prev_thread_u1_pk),
+            prev_pk(&thread.prev_thread_u2_pk),
+            thread.u1_ban,
+            thread.u2_ban,
+            thread.u1_state.last_read,
+            thread.u2_state.last_read,
+            thread.u1_state.muted,
+            thread.u2_state.muted,
+            thread.u1_state.archived,
+            thread.u2_state.archived,
+            slot,
+        ],
+    )?;
+
+    // Threads reveal the users behind their profiles.
+    for user_pk in [&u1_pk, &u2_pk].iter() {
+        conn.execute(
+            "INSERT INTO users (profile, user) VALUES (?1, ?2)
+            ON CONFLICT (profile) DO UPDATE SET user = excluded.user",
+            params![
+                Profile::find_address(user_pk, program_id).0.to_string(),
+                user_pk.to_string()
+            ],
+        )?;
+    }
+
+    // Derive the addresses of both senders for the new message indexes.
+    let known: u32 = conn.query_row(
+        "SELECT COALESCE(MAX(idx), 0) FROM message_keys WHERE thread = ?1",
+        params![address],
+        |row| row.get(0),
+    )?;
+    for index in known + 1..thread.msg_count {
+        for (from_pk, to_pk) in [(&u1_pk, &u2_pk), (&u2_pk, &u1_pk)].iter() {
+            conn.execute(
+                "INSERT OR IGNORE INTO message_keys (address, thread, idx, sender, receiver)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    Message::find_address(index, from_pk, to_pk, program_id)
+                        .0
+                        .to_string(),
+                    address,
+                    index,
+                    from_pk.to_string(),
+                    to_pk.to_string(),
+                ],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn apply_message(
+    conn: &Connection,
+    address: &str,
+    slot: i64,
+    message: &Message,
+) -> Result<(), IndexError> {
+    let body = match message_payload(message) {
+        Ok(Some(MessagePayload::Text(text))) => Some(text),
+        Ok(Some(MessagePayload::Plain(data))) => Some(String::from_utf8_lossy(&data).into()),
+        Ok(Some(MessagePayload::Cta(cta))) => Some(format!("{}\n{}", cta.title, cta.body)),
+        _ => None,
+    };
+    conn.execute(
+        "INSERT INTO messages (address, kind, body, data, timestamp, ttl, edited, prev_msg, slot)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+        ON CONFLICT (address) DO UPDATE SET
+            kind = excluded.kind,
+            body = excluded.body,
+            data = excluded.data,
+            timestamp = excluded.timestamp,
+            ttl = excluded.ttl,
+            edited = excluded.edited,
+            prev_msg = excluded.prev_msg,
+            slot = excluded.slot
+        WHERE excluded.slot >= messages.slot",
+        params![
+            address,
+            message.kind,
+            body,
+            message.msg,
+            message.timestamp,
+            message.ttl,
+            message.edited,
+            message.prev_msg.map(|pk| Pubkey::new(&pk).to_string()),
+            slot,
+        ],
+    )?;
+    Ok(())
+}
+
+fn pubkey(row: &Row, index: usize) -> rusqlite::Result<Pubkey> {
+    let value: String = row.get(index)?;
+    Pubkey::from_str(&value)
+        .map_err(|err| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(err)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use jabber_client::state::{Serdes, ThreadState};
+
+    fn rand_pk() -> Pubkey {
+        Pubkey::new(&rand::random::<[u8; 32]>())
+    }
+
+    fn update<T: Serdes>(slot: u64, address: Pubkey, account: &T) -> AccountUpdate {
+        let mut data = vec![0; account.packed_len().unwrap()];
+        account.pack(&mut data).unwrap();
+        AccountUpdate {
+            slot,
+            address,
+            data: Some(data),
+        }
+    }
+
+    fn text_message(text: &str, timestamp: i64) -> Message {
+        Message {
+            kind: Message::TEXT_KIND,
+            msg: MessagePayload::Text(text.into()).pack().unwrap(),
+            timestamp,
+            ttl: None,
+            edited: None,
+            prev_msg: None,
+            payer: None,
+            meta: vec![],
+        }
+    }
+
+    #[test]
+    fn test_index() {
+        let program_id = rand_pk();
+        let (alice, bob) = (rand_pk(), rand_pk());
+        let (u1_pk, u2_pk) = Thread::ordered_pks(&alice, &bob);
+        let thread_pk = Thread::find_address(&alice, &bob, &program_id).0;
+        let mut thread = Thread {
+            msg_count: 3,
+            prev_thread_u1_pk: None,
+            prev_thread_u2_pk: None,
+            unregistered: false,
+            u1_pk: u1_pk.to_bytes(),
+            u2_pk: u2_pk.to_bytes(),
+            u1_ban: false,
+            u2_ban: false,
+            u1_state: ThreadState::default(),
+            u2_state: ThreadState::default(),
+        };
+        let m1_pk = Message::find_address(1, &alice, &bob, &program_id).0;
+        let m2_pk = Message::find_address(2, &bob, &alice, &program_id).0;
+        let profile = Profile {
+            name: Some("Bob".into()),
+            ..Profile::default()
+        };
+
+        // Messages may arrive before the thread that indexes them.
+        let updates = vec![
+            update(1, m1_pk, &text_message("Hey Bob", 10)),
+            update(2, m2_pk, &text_message("Hey 100% Alice", 20)),
+            update(2, thread_pk, &thread),
+            update(3, Profile::find_address(&bob, &program_id).0, &profile),
+        ];
+        let mut index = Index::open_in_memory(&program_id).unwrap();
+        assert_eq!(
+            index
+                .apply_all(updates.clone().into_iter().map(Ok))
+                .unwrap(),
+            4
+        );
+        // Replays do not change anything.
+        assert_eq!(index.apply_all(updates.into_iter().map(Ok)).unwrap(), 4);
+
+        let inbox = index.inbox(&alice).unwrap();
+        assert_eq!(
+            inbox,
+            vec![InboxThread {
+                thread: thread_pk,
+                other: bob,
+                other_name: Some("Bob".into()),
+                msg_count: 2,
+                unread: 1,
+                last_timestamp: Some(20),
+                muted: false,
+                archived: false,
+            }]
+        );
+        assert_eq!(index.unread_count(&alice).unwrap(), 1);
+        assert_eq!(index.unread_count(&bob).unwrap(), 1);
+
+        let results = index.search(&alice, "100%", 10).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!((results[0].index, results[0].sender), (2, bob));
+        assert!(index.search(&alice, "Hey_", 10).unwrap().is_empty());
+        assert_eq!(index.search(&bob, "hey", 10).unwrap().len(), 2);
+
+        // Alice reads the thread, an older update is ignored.
+        let alice_state = if u1_pk == &alice {
+            &mut thread.u1_state
+        } else {
+            &mut thread.u2_state
+        };
+        alice_state.last_read = 2;
+        index.apply(&update(5, thread_pk, &thread)).unwrap();
+        thread.msg_count = 1;
+        index.apply(&update(4, thread_pk, &thread)).unwrap();
+        assert_eq!(index.unread_count(&alice).unwrap(), 0);
+        assert_eq!(index.inbox(&alice).unwrap()[0].msg_count, 2);
+
+        // Closed messages are removed.
+        index
+            .apply(&AccountUpdate {
+                slot: 6,
+                address: m2_pk,
+                data: None,
+            })
+            .unwrap();
+        assert!(index.search(&alice, "Alice", 10).unwrap().is_empty());
+        assert_eq!(index.inbox(&alice).unwrap()[0].last_timestamp, Some(10));
+    }
+}
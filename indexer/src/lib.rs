@@ -0,0 +1,6 @@
+//! Mirrors Jabber accounts into SQLite from a feed of account updates or a snapshot, and
+//! answers the queries clients need without walking accounts over RPC.
+
+mod index;
+
+pub use index::{InboxThread, Index, IndexError, SearchResult};
@@ -0,0 +1,131 @@
+use clap::{crate_version, value_t, App, AppSettings, Arg, SubCommand};
+use jabber_client::{read_feed, read_snapshot};
+use jabber_indexer::Index;
+use solana_sdk::pubkey::Pubkey;
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufReader},
+    str::FromStr,
+};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+    let user_arg = Arg::with_name("user").required(true).index(1);
+    let matches = App::new("jabber-indexer")
+        .version(crate_version!())
+        .about("Mirror Jabber accounts into SQLite")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("db")
+                .long("db")
+                .value_name("PATH")
+                .takes_value(true)
+                .default_value("jabber.db"),
+        )
+        .arg(
+            Arg::with_name("program_id")
+                .long("program-id")
+                .value_name("PUBKEY")
+                .takes_value(true)
+                .required(true)
+                .env("JABBER_PROGRAM_ID"),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about("Index a saved getProgramAccounts response")
+                .arg(Arg::with_name("file").required(true).index(1))
+                .arg(
+                    Arg::with_name("slot")
+                        .long("slot")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help("Slot the snapshot was taken at"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("feed")
+                .about("Replay a feed of account updates, one JSON object per line")
+                .arg(
+                    Arg::with_name("file")
+                        .index(1)
+                        .help("Feed file, reads stdin when not given"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("inbox")
+                .about("List the threads of a user")
+                .arg(user_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("unread")
+                .about("Count the unread messages of a user")
+                .arg(user_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("search")
+                .about("Search the messages of a user")
+                .arg(user_arg)
+                .arg(Arg::with_name("text").required(true).index(2))
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .default_value("20"),
+                ),
+        )
+        .get_matches();
+
+    let program_id = Pubkey::from_str(matches.value_of("program_id").unwrap())?;
+    let mut index = Index::open(matches.value_of("db").unwrap(), &program_id)?;
+    let user = |matches: &clap::ArgMatches| Pubkey::from_str(matches.value_of("user").unwrap());
+
+    match matches.subcommand() {
+        ("snapshot", Some(matches)) => {
+            let file = File::open(matches.value_of("file").unwrap())?;
+            let updates = read_snapshot(BufReader::new(file), value_t!(matches, "slot", u64)?)?;
+            let count = index.apply_all(updates.into_iter().map(Ok))?;
+            println!("Indexed {} accounts", count);
+        }
+        ("feed", Some(matches)) => {
+            let count = match matches.value_of("file") {
+                Some(path) => index.apply_all(read_feed(BufReader::new(File::open(path)?)))?,
+                None => {
+                    let stdin = io::stdin();
+                    let count = index.apply_all(read_feed(stdin.lock()))?;
+                    count
+                }
+            };
+            println!("Applied {} updates", count);
+        }
+        ("inbox", Some(matches)) => {
+            for thread in index.inbox(&user(matches)?)? {
+                println!(
+                    "{} {} {} messages: {} unread: {}{}",
+                    thread.thread,
+                    thread.other,
+                    thread.other_name.unwrap_or_default(),
+                    thread.msg_count,
+                    thread.unread,
+                    if thread.muted { " muted" } else { "" },
+                );
+            }
+        }
+        ("unread", Some(matches)) => println!("{}", index.unread_count(&user(matches)?)?),
+        ("search", Some(matches)) => {
+            let results = index.search(
+                &user(matches)?,
+                matches.value_of("text").unwrap(),
+                value_t!(matches, "limit", u32)?,
+            )?;
+            for result in results {
+                println!(
+                    "{} {} {} {} {}",
+                    result.thread, result.index, result.sender, result.timestamp, result.body
+                );
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
@@ -0,0 +1,436 @@
+use crate::{
+    sink::{Sink, SinkError},
+    tracker::Notification,
+};
+use log::{info, warn};
+use serde_json::{json, Value};
+use solana_sdk::{clock::UnixTimestamp, pubkey::Pubkey};
+use std::{collections::HashMap, fs, io, path::PathBuf, str::FromStr};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Can not read {0}: {1}")]
+    Io(PathBuf, io::Error),
+    #[error("Invalid JSON in {0}: {1}")]
+    Json(PathBuf, serde_json::Error),
+    #[error("Invalid endpoint registration for {0}")]
+    InvalidEndpoint(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Endpoint {
+    /// Name of the sink delivering to the endpoint.
+    pub sink: String,
+    pub target: String,
+}
+
+/// Endpoints registered by each recipient. Endpoints registered for `*` receive every
+/// notification.
+#[derive(Debug, Default)]
+pub struct Registry {
+    endpoints: HashMap<String, Vec<Endpoint>>,
+}
+
+impl Registry {
+    /// Reads `{"<recipient>": [{"sink": "webhook", "target": "https://.."}], "*": [..]}`.
+    pub fn load(path: &PathBuf) -> Result<Self, ConfigError> {
+        let src = fs::read_to_string(path).map_err(|err| ConfigError::Io(path.clone(), err))?;
+        let value =
+            serde_json::from_str(&src).map_err(|err| ConfigError::Json(path.clone(), err))?;
+        Registry::from_json(&value)
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, ConfigError> {
+        let mut registry = Registry::default();
+        let recipients = value
+            .as_object()
+            .ok_or_else(|| ConfigError::InvalidEndpoint("the registry".into()))?;
+        for (recipient, endpoints) in recipients {
+            let invalid = || ConfigError::InvalidEndpoint(recipient.clone());
+            if recipient != "*" {
+                Pubkey::from_str(recipient).map_err(|_| invalid())?;
+            }
+            let endpoints = endpoints
+                .as_array()
+                .ok_or_else(invalid)?
+                .iter()
+                .map(|endpoint| {
+                    Some(Endpoint {
+                        sink: endpoint.get("sink")?.as_str()?.into(),
+                        target: endpoint.get("target")?.as_str()?.into(),
+                    })
+                })
+                .collect::<Option<_>>()
+                .ok_or_else(invalid)?;
+            registry.endpoints.insert(recipient.clone(), endpoints);
+        }
+        Ok(registry)
+    }
+
+    pub fn endpoints<'a>(&'a self, recipient: &Pubkey) -> impl Iterator<Item = &'a Endpoint> {
+        let all = self.endpoints.get("*").into_iter().flatten();
+        all.chain(
+            self.endpoints
+                .get(&recipient.to_string())
+                .into_iter()
+                .flatten(),
+        )
+    }
+}
+
+/// Deliveries that succeeded, kept in a file so restarts and replays do not deliver twice.
+/// Old deliveries are pruned, notifications due before `pruned_before` count as delivered.
+#[derive(Debug, Default)]
+pub struct DeliveryState {
+    path: Option<PathBuf>,
+    /// Delivery keys and when their notification was due.
+    delivered: HashMap<String, UnixTimestamp>,
+    pruned_before: UnixTimestamp,
+}
+
+impl DeliveryState {
+    /// Loads the state from `path`, starting empty if the file does not exist yet.
+    pub fn load(path: PathBuf) -> Result<Self, ConfigError> {
+        let value = match fs::read_to_string(&path) {
+            Ok(src) => {
+                serde_json::from_str(&src).map_err(|err| ConfigError::Json(path.clone(), err))?
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Value::Null,
+            Err(err) => return Err(ConfigError::Io(path, err)),
+        };
+        let delivered = value
+            .get("delivered")
+            .and_then(Value::as_object)
+            .map(|keys| {
+                keys.iter()
+                    .filter_map(|(key, due)| Some((key.clone(), due.as_i64()?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(DeliveryState {
+            path: Some(path),
+            delivered,
+            pruned_before: value
+                .get("pruned_before")
+                .and_then(Value::as_i64)
+                .unwrap_or_default(),
+        })
+    }
+
+    fn is_delivered(&self, key: &str, due: UnixTimestamp) -> bool {
+        due < self.pruned_before || self.delivered.contains_key(key)
+    }
+
+    /// Forgets the deliveries due more than `retention` seconds before the latest one.
+    fn prune(&mut self, retention: i64) {
+        let latest = match self.delivered.values().max() {
+            Some(latest) => *latest,
+            None => return,
+        };
+        let horizon = latest.saturating_sub(retention);
+        if horizon > self.pruned_before {
+            self.pruned_before = horizon;
+            self.delivered.retain(|_, due| *due >= horizon);
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let delivered: serde_json::Map<_, _> = self
+            .delivered
+            .iter()
+            .map(|(key, due)| (key.clone(), json!(due)))
+            .collect();
+        // Write a new file and rename it, so a crash never leaves a partial state.
+        let tmp = path.with_extension("tmp");
+        let state = json!({ "pruned_before": self.pruned_before, "delivered": delivered });
+        fs::write(&tmp, state.to_string())?;
+        fs::rename(tmp, path)
+    }
+}
+
+struct Delivery {
+    key: String,
+    notification: Notification,
+    endpoint: Endpoint,
+    attempts: u32,
+    due: UnixTimestamp,
+}
+
+/// Schedules notifications to the endpoints of their recipient, retrying failed deliveries
+/// with exponential backoff.
+pub struct Daemon {
+    registry: Registry,
+    sinks: HashMap<String, Box<dyn Sink>>,
+    state: DeliveryState,
+    queue: Vec<Delivery>,
+    pub max_attempts: u32,
+    /// Seconds before the first retry, doubled after every failed attempt.
+    pub retry_delay: i64,
+    /// Seconds deliveries are remembered, counted back from the latest one.
+    pub retention: i64,
+}
+
+impl Daemon {
+    pub const MAX_ATTEMPTS: u32 = 5;
+    pub const RETRY_DELAY: i64 = 10;
+    pub const RETENTION: i64 = 7 * 24 * 60 * 60;
+
+    pub fn new(registry: Registry, state: DeliveryState) -> Self {
+        Daemon {
+            registry,
+            sinks: HashMap::new(),
+            state,
+            queue: vec![],
+            max_attempts: Self::MAX_ATTEMPTS,
+            retry_delay: Self::RETRY_DELAY,
+            retention: Self::RETENTION,
+        }
+    }
+
+    pub fn add_sink(&mut self, name: &str, sink: Box<dyn Sink>) {
+        self.sinks.insert(name.into(), sink);
+    }
+
+    /// Queues the notification for every endpoint of its recipient, at its delivery time.
+    pub fn enqueue(&mut self, notification: Notification) {
+        for endpoint in self.registry.endpoints(&notification.recipient) {
+            let key = format!(
+                "{}:{}:{}:{}",
+                notification.message, notification.recipient, endpoint.sink, endpoint.target
+            );
+            if self.state.is_delivered(&key, notification.deliver_at)
+                || self.queue.iter().any(|d| d.key == key)
+            {
+                continue;
+            }
+            if !self.sinks.contains_key(&endpoint.sink) {
+                warn!("No sink {} for {}", endpoint.sink, notification.recipient);
+                continue;
+            }
+            self.queue.push(Delivery {
+                key,
+                notification: notification.clone(),
+                endpoint: endpoint.clone(),
+                attempts: 0,
+                due: notification.deliver_at,
+            });
+        }
+    }
+
+    /// Delivers the notifications due at `now`, returns how many were delivered.
+    pub fn run_due(&mut self, now: UnixTimestamp) -> io::Result<usize> {
+        let (due, later) = std::mem::take(&mut self.queue)
+            .into_iter()
+            .partition::<Vec<_>, _>(|delivery| delivery.due <= now);
+        self.queue = later;
+
+        let mut delivered = 0;
+        for mut delivery in due {
+            let sink = self.sinks.get_mut(&delivery.endpoint.sink).unwrap();
+            match sink.deliver(&delivery.endpoint.target, &delivery.notification) {
+                Ok(()) => {
+                    self.state
+                        .delivered
+                        .insert(delivery.key, delivery.notification.deliver_at);
+                    delivered += 1;
+                }
+                Err(err) => {
+                    if self.retry(&mut delivery, err, now) {
+                        self.queue.push(delivery);
+                    }
+                }
+            }
+        }
+        if delivered > 0 {
+            self.state.prune(self.retention);
+            self.state.save()?;
+        }
+        Ok(delivered)
+    }
+
+    /// Schedules the next attempt of a failed delivery, false once it is dropped.
+    fn retry(&self, delivery: &mut Delivery, err: SinkError, now: UnixTimestamp) -> bool {
+        delivery.attempts += 1;
+        if delivery.attempts >= self.max_attempts {
+            warn!(
+                "Dropping {} after {} attempts: {}",
+                delivery.key, delivery.attempts, err
+            );
+            return false;
+        }
+        delivery.due = now + (self.retry_delay << (delivery.attempts - 1).min(16));
+        info!(
+            "Delivery of {} failed, retrying at {}: {}",
+            delivery.key, delivery.due, err
+        );
+        true
+    }
+
+    /// Number of queued deliveries.
+    pub fn pending(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// When the next queued delivery is due.
+    pub fn next_due(&self) -> Option<UnixTimestamp> {
+        self.queue.iter().map(|delivery| delivery.due).min()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    type Delivered = Rc<RefCell<Vec<(String, Pubkey)>>>;
+
+    /// Records deliveries, failing the first `failures` of them.
+    struct TestSink {
+        delivered: Delivered,
+        failures: u32,
+    }
+
+    impl Sink for TestSink {
+        fn deliver(&mut self, target: &str, notification: &Notification) -> Result<(), SinkError> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(SinkError::Status(500));
+            }
+            self.delivered
+                .borrow_mut()
+                .push((target.into(), notification.message));
+            Ok(())
+        }
+    }
+
+    fn notification(recipient: Pubkey, deliver_at: UnixTimestamp) -> Notification {
+        Notification {
+            message: Pubkey::new(&rand::random::<[u8; 32]>()),
+            recipient,
+            sender: Pubkey::new(&[1; 32]),
+            channel: None,
+            index: 1,
+            kind: 0,
+            title: None,
+            body: Some("Hey".into()),
+            timestamp: 100,
+            deliver_at,
+        }
+    }
+
+    fn test_daemon(registry: Value, failures: u32) -> (Daemon, Delivered) {
+        let delivered = Rc::new(RefCell::new(vec![]));
+        let mut daemon = Daemon::new(
+            Registry::from_json(&registry).unwrap(),
+            DeliveryState::default(),
+        );
+        daemon.add_sink(
+            "test",
+            Box::new(TestSink {
+                delivered: delivered.clone(),
+                failures,
+            }),
+        );
+        (daemon, delivered)
+    }
+
+    #[test]
+    fn test_registry() {
+        let recipient = Pubkey::new(&[2; 32]);
+        let registry = Registry::from_json(&json!({
+            recipient.to_string(): [{ "sink": "webhook", "target": "https://example.com" }],
+            "*": [{ "sink": "stdout", "target": "log" }],
+        }))
+        .unwrap();
+        let sinks: Vec<_> = registry
+            .endpoints(&recipient)
+            .map(|e| e.sink.as_str())
+            .collect();
+        assert_eq!(sinks, vec!["stdout", "webhook"]);
+        assert_eq!(registry.endpoints(&Pubkey::new(&[3; 32])).count(), 1);
+
+        assert!(Registry::from_json(&json!({ "alice": [] })).is_err());
+        assert!(Registry::from_json(&json!({ "*": [{ "sink": "stdout" }] })).is_err());
+    }
+
+    #[test]
+    fn test_delivery() {
+        let recipient = Pubkey::new(&[2; 32]);
+        let registry = json!({ recipient.to_string(): [{ "sink": "test", "target": "a" }] });
+        let (mut daemon, delivered) = test_daemon(registry, 0);
+
+        // Delivered at the CTA delivery time, once.
+        let cta = notification(recipient, 200);
+        daemon.enqueue(cta.clone());
+        daemon.enqueue(cta.clone());
+        daemon.enqueue(notification(Pubkey::new(&[3; 32]), 100));
+        assert_eq!(daemon.pending(), 1);
+        assert_eq!(daemon.run_due(199).unwrap(), 0);
+        assert_eq!(daemon.next_due(), Some(200));
+        assert_eq!(daemon.run_due(200).unwrap(), 1);
+        assert_eq!(*delivered.borrow(), vec![("a".to_string(), cta.message)]);
+        daemon.enqueue(cta);
+        assert_eq!(daemon.pending(), 0);
+    }
+
+    #[test]
+    fn test_retry() {
+        let recipient = Pubkey::new(&[2; 32]);
+        let registry = json!({ recipient.to_string(): [{ "sink": "test", "target": "a" }] });
+        let (mut daemon, delivered) = test_daemon(registry.clone(), 2);
+        daemon.enqueue(notification(recipient, 0));
+        assert_eq!(daemon.run_due(0).unwrap(), 0);
+        assert_eq!(daemon.next_due(), Some(10));
+        assert_eq!(daemon.run_due(10).unwrap(), 0);
+        assert_eq!(daemon.next_due(), Some(30));
+        assert_eq!(daemon.run_due(30).unwrap(), 1);
+        assert_eq!(delivered.borrow().len(), 1);
+        assert_eq!(daemon.pending(), 0);
+
+        // Dropped after `max_attempts`.
+        let (mut daemon, delivered) = test_daemon(registry, 10);
+        daemon.max_attempts = 2;
+        daemon.enqueue(notification(recipient, 0));
+        daemon.run_due(0).unwrap();
+        daemon.run_due(100).unwrap();
+        assert_eq!(daemon.pending(), 0);
+        assert!(delivered.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_delivery_state() {
+        let path =
+            std::env::temp_dir().join(format!("jabber-notifier-{}.json", rand::random::<u64>()));
+        let recipient = Pubkey::new(&[2; 32]);
+        let registry = json!({ recipient.to_string(): [{ "sink": "test", "target": "a" }] });
+        let sent = notification(recipient, 0);
+
+        let (mut daemon, _) = test_daemon(registry.clone(), 0);
+        daemon.state = DeliveryState::load(path.clone()).unwrap();
+        daemon.enqueue(sent.clone());
+        assert_eq!(daemon.run_due(0).unwrap(), 1);
+
+        // A restarted daemon does not deliver again.
+        let (mut daemon, _) = test_daemon(registry.clone(), 0);
+        daemon.state = DeliveryState::load(path.clone()).unwrap();
+        daemon.enqueue(sent.clone());
+        assert_eq!(daemon.pending(), 0);
+
+        // Deliveries older than the retention are forgotten, but still not delivered again.
+        daemon.retention = 100;
+        daemon.enqueue(notification(recipient, 150));
+        assert_eq!(daemon.run_due(150).unwrap(), 1);
+        assert_eq!(daemon.state.delivered.len(), 1);
+        let (mut daemon, _) = test_daemon(registry, 0);
+        daemon.state = DeliveryState::load(path.clone()).unwrap();
+        assert_eq!(daemon.state.pruned_before, 50);
+        daemon.enqueue(sent);
+        assert_eq!(daemon.pending(), 0);
+        fs::remove_file(path).unwrap();
+    }
+}
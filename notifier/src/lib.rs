@@ -0,0 +1,12 @@
+//! Watches Jabber message accounts and pushes notifications for new messages to the
+//! endpoints recipients registered, through pluggable delivery sinks.
+
+mod daemon;
+mod rpc;
+mod sink;
+mod tracker;
+
+pub use daemon::{ConfigError, Daemon, DeliveryState, Endpoint, Registry};
+pub use rpc::{PollError, RpcPoller};
+pub use sink::{Sink, SinkError, WebhookSink, WriterSink};
+pub use tracker::{Notification, Tracker};
@@ -0,0 +1,189 @@
+use clap::{crate_version, value_t, App, AppSettings, Arg, SubCommand};
+use jabber_client::{read_feed, AccountUpdate};
+use jabber_notifier::{
+    Daemon, DeliveryState, Registry, RpcPoller, Tracker, WebhookSink, WriterSink,
+};
+use log::{info, warn};
+use solana_sdk::{clock::UnixTimestamp, pubkey::Pubkey};
+use std::{
+    error::Error,
+    fs::{File, OpenOptions},
+    io::{self, BufReader},
+    path::PathBuf,
+    str::FromStr,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+fn now() -> UnixTimestamp {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as UnixTimestamp
+}
+
+/// Feeds an update through the tracker and queues the notifications sent after `since`.
+fn enqueue(tracker: &mut Tracker, daemon: &mut Daemon, update: &AccountUpdate, since: i64) {
+    for notification in tracker.update(update) {
+        if notification.timestamp >= since {
+            info!(
+                "Message {} from {} to {}",
+                notification.message, notification.sender, notification.recipient
+            );
+            daemon.enqueue(notification);
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+    let matches = App::new("jabber-notifier")
+        .version(crate_version!())
+        .about("Push notifications for new Jabber messages")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("program_id")
+                .long("program-id")
+                .value_name("PUBKEY")
+                .takes_value(true)
+                .required(true)
+                .env("JABBER_PROGRAM_ID"),
+        )
+        .arg(
+            Arg::with_name("endpoints")
+                .long("endpoints")
+                .value_name("FILE")
+                .takes_value(true)
+                .required(true)
+                .help("JSON file with the endpoints registered by each recipient"),
+        )
+        .arg(
+            Arg::with_name("state")
+                .long("state")
+                .value_name("FILE")
+                .takes_value(true)
+                .default_value("notifier-state.json")
+                .help("Where delivered notifications are recorded"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("File the \"file\" sink appends notifications to"),
+        )
+        .arg(
+            Arg::with_name("since")
+                .long("since")
+                .value_name("TIMESTAMP")
+                .takes_value(true)
+                .help("Skip messages sent before this unix timestamp, watch defaults to its start time"),
+        )
+        .arg(
+            Arg::with_name("retention")
+                .long("retention")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .default_value("604800")
+                .help("How long delivered messages are remembered, older ones are not notified"),
+        )
+        .arg(
+            Arg::with_name("max_attempts")
+                .long("max-attempts")
+                .takes_value(true)
+                .default_value("5"),
+        )
+        .arg(
+            Arg::with_name("retry_delay")
+                .long("retry-delay")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .default_value("10")
+                .help("Delay before the first retry, doubled after every failure"),
+        )
+        .subcommand(
+            SubCommand::with_name("feed")
+                .about("Replay a feed of account updates, one JSON object per line")
+                .arg(
+                    Arg::with_name("file")
+                        .index(1)
+                        .help("Feed file, reads stdin when not given"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Poll the program accounts of a validator")
+                .arg(
+                    Arg::with_name("url")
+                        .long("url")
+                        .takes_value(true)
+                        .default_value("http://localhost:8899"),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .value_name("SECONDS")
+                        .takes_value(true)
+                        .default_value("2"),
+                ),
+        )
+        .get_matches();
+
+    let program_id = Pubkey::from_str(matches.value_of("program_id").unwrap())?;
+    let registry = Registry::load(&PathBuf::from(matches.value_of("endpoints").unwrap()))?;
+    let state = DeliveryState::load(PathBuf::from(matches.value_of("state").unwrap()))?;
+    let since = match matches.value_of("since") {
+        Some(_) => value_t!(matches, "since", i64)?,
+        // Watching only pushes new messages, replaying a feed starts at its beginning.
+        None if matches.subcommand_name() == Some("watch") => now(),
+        None => 0,
+    };
+    let mut daemon = Daemon::new(registry, state);
+    daemon.max_attempts = value_t!(matches, "max_attempts", u32)?;
+    daemon.retry_delay = value_t!(matches, "retry_delay", i64)?;
+    daemon.retention = value_t!(matches, "retention", i64)?;
+    daemon.add_sink("webhook", Box::new(WebhookSink::new()));
+    daemon.add_sink("stdout", Box::new(WriterSink::new(io::stdout())));
+    if let Some(path) = matches.value_of("output") {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        daemon.add_sink("file", Box::new(WriterSink::new(file)));
+    }
+    let mut tracker = Tracker::new(&program_id);
+    tracker.retention = daemon.retention;
+
+    match matches.subcommand() {
+        ("feed", Some(matches)) => {
+            let updates: Box<dyn Iterator<Item = _>> = match matches.value_of("file") {
+                Some(path) => Box::new(read_feed(BufReader::new(File::open(path)?))),
+                None => Box::new(read_feed(BufReader::new(io::stdin()))),
+            };
+            for update in updates {
+                enqueue(&mut tracker, &mut daemon, &update?, since);
+                daemon.run_due(now())?;
+            }
+            // Wait for retries and CTAs scheduled later.
+            while let Some(due) = daemon.next_due() {
+                thread::sleep(Duration::from_secs((due - now()).max(0) as u64));
+                daemon.run_due(now())?;
+            }
+        }
+        ("watch", Some(matches)) => {
+            let mut poller = RpcPoller::new(matches.value_of("url").unwrap(), &program_id);
+            let interval = Duration::from_secs(value_t!(matches, "interval", u64)?);
+            loop {
+                match poller.poll() {
+                    Ok(updates) => {
+                        for update in updates {
+                            enqueue(&mut tracker, &mut daemon, &update, since);
+                        }
+                    }
+                    Err(err) => warn!("Poll failed: {}", err),
+                }
+                daemon.run_due(now())?;
+                thread::sleep(interval);
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use jabber_client::{read_snapshot, AccountUpdate, FeedError};
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashMap;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PollError {
+    #[error("Request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Invalid response to {0}")]
+    InvalidResponse(&'static str),
+    #[error("Invalid accounts: {0}")]
+    Feed(#[from] FeedError),
+}
+
+/// Polls the program accounts of a validator, reporting the accounts that changed since the
+/// previous poll.
+pub struct RpcPoller {
+    client: reqwest::blocking::Client,
+    url: String,
+    program_id: Pubkey,
+    accounts: HashMap<Pubkey, Vec<u8>>,
+}
+
+impl RpcPoller {
+    pub fn new(url: &str, program_id: &Pubkey) -> Self {
+        RpcPoller {
+            client: reqwest::blocking::Client::new(),
+            url: url.into(),
+            program_id: *program_id,
+            accounts: HashMap::new(),
+        }
+    }
+
+    fn request(&self, method: &'static str, params: Value) -> Result<Value, PollError> {
+        let response: Value = self
+            .client
+            .post(&self.url)
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": method,
+                "params": params,
+            }))
+            .send()?
+            .json()?;
+        response
+            .get("result")
+            .cloned()
+            .ok_or(PollError::InvalidResponse(method))
+    }
+
+    /// Updates for the new, changed and closed accounts since the last poll. The first poll
+    /// reports every account.
+    pub fn poll(&mut self) -> Result<Vec<AccountUpdate>, PollError> {
+        let slot = self
+            .request("getSlot", json!([]))?
+            .as_u64()
+            .ok_or(PollError::InvalidResponse("getSlot"))?;
+        let accounts = self.request(
+            "getProgramAccounts",
+            json!([self.program_id.to_string(), { "encoding": "base64" }]),
+        )?;
+        let accounts = read_snapshot(accounts.to_string().as_bytes(), slot)?;
+        Ok(self.diff(accounts, slot))
+    }
+
+    fn diff(&mut self, accounts: Vec<AccountUpdate>, slot: u64) -> Vec<AccountUpdate> {
+        let mut previous = std::mem::take(&mut self.accounts);
+        let mut updates = vec![];
+        for update in accounts {
+            let data = update.data.clone().unwrap_or_default();
+            self.accounts.insert(update.address, data.clone());
+            if previous.remove(&update.address) != Some(data) {
+                updates.push(update);
+            }
+        }
+        updates.extend(previous.keys().map(|address| AccountUpdate {
+            slot,
+            address: *address,
+            data: None,
+        }));
+        updates
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let (a, b, c) = (
+            Pubkey::new(&[1; 32]),
+            Pubkey::new(&[2; 32]),
+            Pubkey::new(&[3; 32]),
+        );
+        let update = |address, data: &[u8], slot| AccountUpdate {
+            slot,
+            address,
+            data: Some(data.to_vec()),
+        };
+        let mut poller = RpcPoller::new("http://localhost:8899", &Pubkey::new(&[4; 32]));
+        let first = vec![update(a, &[1], 1), update(b, &[2], 1)];
+        assert_eq!(poller.diff(first.clone(), 1), first);
+
+        let updates = poller.diff(vec![update(a, &[1], 2), update(c, &[3], 2)], 2);
+        assert_eq!(
+            updates,
+            vec![
+                update(c, &[3], 2),
+                AccountUpdate {
+                    slot: 2,
+                    address: b,
+                    data: None,
+                },
+            ]
+        );
+        assert!(poller
+            .diff(vec![update(a, &[1], 3), update(c, &[3], 3)], 3)
+            .is_empty());
+    }
+}
@@ -0,0 +1,76 @@
+use crate::tracker::Notification;
+use serde_json::json;
+use std::io::{self, Write};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SinkError {
+    #[error("Write failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("Request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Endpoint responded with status {0}")]
+    Status(u16),
+}
+
+/// Delivers notifications to the endpoints users registered with it, `target` is the
+/// sink specific address of the endpoint.
+pub trait Sink {
+    fn deliver(&mut self, target: &str, notification: &Notification) -> Result<(), SinkError>;
+}
+
+/// Posts the notification as JSON to the `target` URL.
+pub struct WebhookSink {
+    client: reqwest::blocking::Client,
+}
+
+impl WebhookSink {
+    pub fn new() -> Self {
+        WebhookSink {
+            client: reqwest::blocking::Client::new(),
+        }
+    }
+}
+
+impl Default for WebhookSink {
+    fn default() -> Self {
+        WebhookSink::new()
+    }
+}
+
+impl Sink for WebhookSink {
+    fn deliver(&mut self, target: &str, notification: &Notification) -> Result<(), SinkError> {
+        let response = self
+            .client
+            .post(target)
+            .json(&notification.to_json())
+            .send()?;
+        if !response.status().is_success() {
+            return Err(SinkError::Status(response.status().as_u16()));
+        }
+        Ok(())
+    }
+}
+
+/// Writes one JSON object per notification, to stdout or a file.
+pub struct WriterSink<W> {
+    writer: W,
+}
+
+impl<W: Write> WriterSink<W> {
+    pub fn new(writer: W) -> Self {
+        WriterSink { writer }
+    }
+}
+
+impl<W: Write> Sink for WriterSink<W> {
+    fn deliver(&mut self, target: &str, notification: &Notification) -> Result<(), SinkError> {
+        let line = json!({
+            "target": target,
+            "notification": notification.to_json(),
+        });
+        writeln!(self.writer, "{}", line)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
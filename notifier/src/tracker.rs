@@ -0,0 +1,467 @@
+use jabber_client::{
+    message_payload,
+    state::{Channel, Message, MessagePayload, Subscription, Thread},
+    AccountUpdate, JabberAccount,
+};
+use log::warn;
+use serde_json::{json, Value};
+use solana_sdk::{clock::UnixTimestamp, pubkey::Pubkey};
+use std::collections::HashMap;
+
+/// A message to push to one recipient.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notification {
+    pub message: Pubkey,
+    pub recipient: Pubkey,
+    pub sender: Pubkey,
+    /// Set for messages published on a channel.
+    pub channel: Option<Pubkey>,
+    pub index: u32,
+    pub kind: u8,
+    pub title: Option<String>,
+    /// `None` for encrypted and custom messages, clients fetch those themselves.
+    pub body: Option<String>,
+    pub timestamp: UnixTimestamp,
+    /// The CTA delivery time, otherwise when the message was sent.
+    pub deliver_at: UnixTimestamp,
+}
+
+impl Notification {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "message": self.message.to_string(),
+            "recipient": self.recipient.to_string(),
+            "sender": self.sender.to_string(),
+            "channel": self.channel.map(|pk| pk.to_string()),
+            "index": self.index,
+            "kind": self.kind,
+            "title": self.title,
+            "body": self.body,
+            "timestamp": self.timestamp,
+            "deliver_at": self.deliver_at,
+        })
+    }
+}
+
+/// Where a message account sits, messages do not store their sender or receiver.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MessageKey {
+    Direct {
+        sender: Pubkey,
+        receiver: Pubkey,
+        index: u32,
+    },
+    Published {
+        publisher: Pubkey,
+        channel: Pubkey,
+        index: u32,
+    },
+}
+
+/// Turns account updates into notifications for new messages. Message addresses are derived
+/// from the threads and channels, messages seen before their thread or channel are held
+/// until it shows up.
+///
+/// Messages sent more than `retention` seconds before the newest one are forgotten and no
+/// longer notified.
+pub struct Tracker {
+    program_id: Pubkey,
+    pub retention: i64,
+    /// Timestamp of the newest message.
+    newest: UnixTimestamp,
+    pruned_before: UnixTimestamp,
+    /// Message indexes with derived addresses, per thread or channel.
+    known_counts: HashMap<Pubkey, u32>,
+    /// Keys of the messages not seen yet, with the newest timestamp when they were derived.
+    message_keys: HashMap<Pubkey, (MessageKey, UnixTimestamp)>,
+    /// Subscription address to channel, subscriber and first index.
+    subscriptions: HashMap<Pubkey, (Pubkey, Pubkey, u32)>,
+    pending: HashMap<Pubkey, Message>,
+    /// Timestamps of the notified messages, edits update them again.
+    notified: HashMap<Pubkey, UnixTimestamp>,
+}
+
+impl Tracker {
+    pub const RETENTION: i64 = 7 * 24 * 60 * 60;
+    /// How far the retention horizon moves before the tracker is pruned again.
+    const PRUNE_INTERVAL: i64 = 60 * 60;
+
+    pub fn new(program_id: &Pubkey) -> Self {
+        Tracker {
+            program_id: *program_id,
+            retention: Self::RETENTION,
+            newest: 0,
+            pruned_before: 0,
+            known_counts: HashMap::new(),
+            message_keys: HashMap::new(),
+            subscriptions: HashMap::new(),
+            pending: HashMap::new(),
+            notified: HashMap::new(),
+        }
+    }
+
+    pub fn update(&mut self, update: &AccountUpdate) -> Vec<Notification> {
+        let data = match &update.data {
+            Some(data) => data,
+            None => {
+                let address = &update.address;
+                self.known_counts.remove(address);
+                self.message_keys.remove(address);
+                self.subscriptions.remove(address);
+                self.pending.remove(address);
+                self.notified.remove(address);
+                return vec![];
+            }
+        };
+        match JabberAccount::unpack(data) {
+            Ok(JabberAccount::Message(message)) => self.message(update.address, message),
+            Ok(JabberAccount::Thread(thread)) => self.thread(update.address, &thread),
+            Ok(JabberAccount::Channel(channel)) => self.channel(update.address, &channel),
+            Ok(JabberAccount::Subscription(subscription)) => {
+                self.subscription(update.address, &subscription);
+                vec![]
+            }
+            Ok(_) => vec![],
+            Err(err) => {
+                warn!("Skipping account {}: {}", update.address, err);
+                vec![]
+            }
+        }
+    }
+
+    fn message(&mut self, address: Pubkey, message: Message) -> Vec<Notification> {
+        // Edits and annotations update the account again.
+        if self.notified.contains_key(&address) || message.timestamp < self.horizon() {
+            return vec![];
+        }
+        self.newest = self.newest.max(message.timestamp);
+        let notifications = match self.message_keys.get(&address) {
+            Some((key, _)) => {
+                let key = *key;
+                self.notify(address, key, &message)
+            }
+            None => {
+                self.pending.insert(address, message);
+                vec![]
+            }
+        };
+        self.prune();
+        notifications
+    }
+
+    /// Messages sent before this are forgotten.
+    fn horizon(&self) -> UnixTimestamp {
+        self.newest.saturating_sub(self.retention)
+    }
+
+    fn prune(&mut self) {
+        let horizon = self.horizon();
+        if horizon < self.pruned_before.saturating_add(Self::PRUNE_INTERVAL) {
+            return;
+        }
+        // Keys are kept until the next pruning, so the messages of a thread derived before
+        // the newest timestamp jumped still find theirs.
+        let previous = std::mem::replace(&mut self.pruned_before, horizon);
+        self.message_keys
+            .retain(|_, (_, derived)| *derived >= previous);
+        self.pending
+            .retain(|_, message| message.timestamp >= horizon);
+        self.notified.retain(|_, timestamp| *timestamp >= horizon);
+    }
+
+    fn thread(&mut self, address: Pubkey, thread: &Thread) -> Vec<Notification> {
+        let u1_pk = Pubkey::new(&thread.u1_pk);
+        let u2_pk = Pubkey::new(&thread.u2_pk);
+        let program_id = self.program_id;
+        self.add_keys(address, thread.msg_count, |index| {
+            [(u1_pk, u2_pk), (u2_pk, u1_pk)]
+                .iter()
+                .map(|(sender, receiver)| {
+                    (
+                        Message::find_address(index, sender, receiver, &program_id).0,
+                        MessageKey::Direct {
+                            sender: *sender,
+                            receiver: *receiver,
+                            index,
+                        },
+                    )
+                })
+                .collect()
+        })
+    }
+
+    fn channel(&mut self, address: Pubkey, channel: &Channel) -> Vec<Notification> {
+        let publisher = Pubkey::new(&channel.publisher);
+        let program_id = self.program_id;
+        self.add_keys(address, channel.msg_count, |index| {
+            vec![(
+                Channel::message_address(index, &publisher, &address, &program_id).0,
+                MessageKey::Published {
+                    publisher,
+                    channel: address,
+                    index,
+                },
+            )]
+        })
+    }
+
+    fn subscription(&mut self, address: Pubkey, subscription: &Subscription) {
+        self.subscriptions.insert(
+            address,
+            (
+                Pubkey::new(&subscription.channel),
+                Pubkey::new(&subscription.subscriber),
+                subscription.since,
+            ),
+        );
+    }
+
+    /// Derives the message addresses for the new indexes below `msg_count`, and notifies
+    /// pending messages at them.
+    fn add_keys<F>(&mut self, address: Pubkey, msg_count: u32, keys: F) -> Vec<Notification>
+    where
+        F: Fn(u32) -> Vec<(Pubkey, MessageKey)>,
+    {
+        let known = self.known_counts.get(&address).copied().unwrap_or(1);
+        if msg_count <= known {
+            return vec![];
+        }
+        self.known_counts.insert(address, msg_count);
+        let mut notifications = vec![];
+        for index in known..msg_count {
+            for (msg_pk, key) in keys(index) {
+                self.message_keys.insert(msg_pk, (key, self.newest));
+                if let Some(message) = self.pending.remove(&msg_pk) {
+                    notifications.extend(self.notify(msg_pk, key, &message));
+                }
+            }
+        }
+        notifications
+    }
+
+    fn notify(&mut self, address: Pubkey, key: MessageKey, message: &Message) -> Vec<Notification> {
+        self.message_keys.remove(&address);
+        self.notified.insert(address, message.timestamp);
+        if message.kind == Message::RETRACTED_KIND {
+            return vec![];
+        }
+        let (title, body, deliver_at) = match message_payload(message) {
+            Ok(Some(MessagePayload::Text(text))) => (None, Some(text), message.timestamp),
+            Ok(Some(MessagePayload::Plain(data))) => (
+                None,
+                Some(String::from_utf8_lossy(&data).into()),
+                message.timestamp,
+            ),
+            Ok(Some(MessagePayload::Cta(cta))) => (Some(cta.title), Some(cta.body), cta.delivery),
+            _ => (None, None, message.timestamp),
+        };
+        let (sender, channel, index, recipients) = match key {
+            MessageKey::Direct {
+                sender,
+                receiver,
+                index,
+            } => (sender, None, index, vec![receiver]),
+            MessageKey::Published {
+                publisher,
+                channel,
+                index,
+            } => {
+                let subscribers = self
+                    .subscriptions
+                    .values()
+                    .filter(|(c, _, since)| *c == channel && *since <= index)
+                    .map(|(_, subscriber, _)| *subscriber)
+                    .collect();
+                (publisher, Some(channel), index, subscribers)
+            }
+        };
+        recipients
+            .into_iter()
+            .map(|recipient| Notification {
+                message: address,
+                recipient,
+                sender,
+                channel,
+                index,
+                kind: message.kind,
+                title: title.clone(),
+                body: body.clone(),
+                timestamp: message.timestamp,
+                deliver_at,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use jabber_client::state::{CtaMessage, Serdes, ThreadState};
+
+    fn rand_pk() -> Pubkey {
+        Pubkey::new(&rand::random::<[u8; 32]>())
+    }
+
+    fn update<T: Serdes>(address: Pubkey, account: &T) -> AccountUpdate {
+        let mut data = vec![0; account.packed_len().unwrap()];
+        account.pack(&mut data).unwrap();
+        AccountUpdate {
+            slot: 1,
+            address,
+            data: Some(data),
+        }
+    }
+
+    fn message(payload: MessagePayload) -> Message {
+        Message {
+            kind: payload.kind(),
+            msg: payload.pack().unwrap(),
+            timestamp: 100,
+            ttl: None,
+            edited: None,
+            prev_msg: None,
+            payer: None,
+            meta: vec![],
+        }
+    }
+
+    #[test]
+    fn test_thread_messages() {
+        let program_id = rand_pk();
+        let (alice, bob) = (rand_pk(), rand_pk());
+        let (u1_pk, u2_pk) = Thread::ordered_pks(&alice, &bob);
+        let mut thread = Thread {
+            msg_count: 2,
+            prev_thread_u1_pk: None,
+            prev_thread_u2_pk: None,
+            unregistered: false,
+            u1_pk: u1_pk.to_bytes(),
+            u2_pk: u2_pk.to_bytes(),
+            u1_ban: false,
+            u2_ban: false,
+            u1_state: ThreadState::default(),
+            u2_state: ThreadState::default(),
+        };
+        let thread_pk = Thread::find_address(&alice, &bob, &program_id).0;
+        let m1_pk = Message::find_address(1, &alice, &bob, &program_id).0;
+        let m2_pk = Message::find_address(2, &bob, &alice, &program_id).0;
+        let mut tracker = Tracker::new(&program_id);
+
+        // The message is held until its thread shows up.
+        let hey = message(MessagePayload::Text("Hey".into()));
+        assert!(tracker.update(&update(m1_pk, &hey)).is_empty());
+        let notifications = tracker.update(&update(thread_pk, &thread));
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(
+            (notifications[0].sender, notifications[0].recipient),
+            (alice, bob)
+        );
+        assert_eq!(notifications[0].body, Some("Hey".into()));
+        // Edits are not notified again.
+        assert!(tracker.update(&update(m1_pk, &hey)).is_empty());
+
+        // CTAs are delivered at their delivery time.
+        thread.msg_count = 3;
+        assert!(tracker.update(&update(thread_pk, &thread)).is_empty());
+        let cta = message(MessagePayload::Cta(CtaMessage {
+            title: "Liquidation".into(),
+            body: "Top up".into(),
+            delivery: 500,
+        }));
+        let notifications = tracker.update(&update(m2_pk, &cta));
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].recipient, alice);
+        assert_eq!(notifications[0].title, Some("Liquidation".into()));
+        assert_eq!(notifications[0].deliver_at, 500);
+    }
+
+    #[test]
+    fn test_pruning() {
+        let program_id = rand_pk();
+        let (alice, bob) = (rand_pk(), rand_pk());
+        let (u1_pk, u2_pk) = Thread::ordered_pks(&alice, &bob);
+        let thread = Thread {
+            msg_count: 3,
+            prev_thread_u1_pk: None,
+            prev_thread_u2_pk: None,
+            unregistered: false,
+            u1_pk: u1_pk.to_bytes(),
+            u2_pk: u2_pk.to_bytes(),
+            u1_ban: false,
+            u2_ban: false,
+            u1_state: ThreadState::default(),
+            u2_state: ThreadState::default(),
+        };
+        let thread_pk = Thread::find_address(&alice, &bob, &program_id).0;
+        let m1_pk = Message::find_address(1, &alice, &bob, &program_id).0;
+        let m2_pk = Message::find_address(2, &bob, &alice, &program_id).0;
+        let mut tracker = Tracker::new(&program_id);
+        tracker.retention = 100;
+        tracker.update(&update(thread_pk, &thread));
+
+        let old = message(MessagePayload::Text("Hey".into()));
+        assert_eq!(tracker.update(&update(m1_pk, &old)).len(), 1);
+        let mut new = message(MessagePayload::Text("Hey yourself".into()));
+        new.timestamp = old.timestamp + tracker.retention + Tracker::PRUNE_INTERVAL;
+        assert_eq!(tracker.update(&update(m2_pk, &new)).len(), 1);
+        assert_eq!(tracker.notified.len(), 1);
+
+        // Edits of forgotten messages are not notified again.
+        assert!(tracker.update(&update(m1_pk, &old)).is_empty());
+        assert!(tracker.pending.is_empty());
+
+        // Closed accounts are forgotten.
+        for address in [thread_pk, m2_pk].iter() {
+            tracker.update(&AccountUpdate {
+                slot: 2,
+                address: *address,
+                data: None,
+            });
+        }
+        assert!(tracker.notified.is_empty());
+        assert!(tracker.known_counts.is_empty());
+    }
+
+    #[test]
+    fn test_published_messages() {
+        let program_id = rand_pk();
+        let publisher = rand_pk();
+        let (early, late) = (rand_pk(), rand_pk());
+        let channel_pk = Channel::find_address(&publisher, "alerts", &program_id).0;
+        let mut tracker = Tracker::new(&program_id);
+        for (subscriber, since) in [(early, 1), (late, 2)].iter() {
+            let subscription = Subscription {
+                channel: channel_pk.to_bytes(),
+                subscriber: subscriber.to_bytes(),
+                since: *since,
+            };
+            let address = Subscription::find_address(&channel_pk, subscriber, &program_id).0;
+            tracker.update(&update(address, &subscription));
+        }
+
+        let mut channel = Channel {
+            publisher: publisher.to_bytes(),
+            name: "alerts".into(),
+            subscriber_count: 2,
+            msg_count: 2,
+        };
+        tracker.update(&update(channel_pk, &channel));
+        let m1_pk = Channel::message_address(1, &publisher, &channel_pk, &program_id).0;
+        let notifications =
+            tracker.update(&update(m1_pk, &message(MessagePayload::Text("1".into()))));
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].recipient, early);
+        assert_eq!(notifications[0].channel, Some(channel_pk));
+
+        channel.msg_count = 3;
+        tracker.update(&update(channel_pk, &channel));
+        let m2_pk = Channel::message_address(2, &publisher, &channel_pk, &program_id).0;
+        let notifications =
+            tracker.update(&update(m2_pk, &message(MessagePayload::Text("2".into()))));
+        let mut recipients: Vec<_> = notifications.iter().map(|n| n.recipient).collect();
+        recipients.sort();
+        let mut expected = vec![early, late];
+        expected.sort();
+        assert_eq!(recipients, expected);
+    }
+}
@@ -12,6 +12,50 @@ pub enum JabberError {
     AccountNotRentExempt = 2,
     #[error("Chat thread exists")]
     ChatThreadExists = 3,
+    #[error("Insufficient lamports for message fee")]
+    InsufficientMessageFee = 4,
+    #[error("Thread is banned")]
+    ThreadBanned = 5,
+    #[error("Message has not expired")]
+    MessageNotExpired = 6,
+    #[error("Account already initialized")]
+    AccountAlreadyInitialized = 7,
+    #[error("Account layout version is not supported")]
+    AccountVersionMismatch = 8,
+    #[error("Profile name or bio is too long")]
+    ProfileTooLarge = 9,
+    #[error("Message does not fit the message account")]
+    MessageTooLarge = 10,
+    #[error("Parent message is not in the thread")]
+    InvalidParentMessage = 11,
+    #[error("Too many metadata entries on the message")]
+    TooManyMessageMeta = 12,
+    #[error("Message index is not in the thread")]
+    InvalidMessageIndex = 13,
+    #[error("Message has been retracted")]
+    MessageRetracted = 14,
+    #[error("Message kind is not supported")]
+    UnsupportedMessageKind = 15,
+    #[error("Message payload does not match its kind")]
+    InvalidMessagePayload = 16,
+    #[error("CTA delivery time is in the past")]
+    DeliveryInPast = 17,
+    #[error("Message is not sealed to the receiver's current key")]
+    EncryptionKeyMismatch = 18,
+    #[error("Signer is not a member of the group")]
+    NotGroupMember = 19,
+    #[error("Group member limit reached")]
+    GroupMemberLimit = 20,
+    #[error("Only the group owner can do this")]
+    NotGroupOwner = 21,
+    #[error("Only group admins can do this")]
+    NotGroupAdmin = 22,
+    #[error("Posting policy does not allow the signer to post")]
+    GroupPostingRestricted = 23,
+    #[error("Channel name is empty or too long")]
+    InvalidChannelName = 24,
+    #[error("Message ttl has to be positive")]
+    InvalidTtl = 25,
 }
 impl From<JabberError> for ProgramError {
     fn from(e: JabberError) -> Self {
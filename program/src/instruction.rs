@@ -1,42 +1,270 @@
 use crate::error::JabberError;
-use crate::state::{Jabber, Message, Profile, Serdes, Thread};
+use crate::state::{
+    owner_account, Channel, Group, GroupMessage, GroupRole, Jabber, Message, MessageMeta,
+    MessagePayload, MetaKind, PostPolicy, Profile, PublicKey, Serdes, Subscription, Thread,
+    ThreadState,
+};
 
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_sdk::{
     account_info::next_account_info,
     account_info::AccountInfo,
-    clock::Clock,
+    clock::{Clock, UnixTimestamp},
     entrypoint::ProgramResult,
     info,
+    instruction::{AccountMeta, Instruction},
+    program::{invoke, invoke_signed},
     program_error::ProgramError,
     pubkey::Pubkey,
     rent::Rent,
+    system_instruction, system_program,
     sysvar::{clock, rent, Sysvar},
 };
 
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub enum JabberInstruction {
-    /// 0. `[is_signer]`
-    /// 1. `[writable]` Signer's UerProfile account'
+    /// 0. `[is_signer, writable]`
+    /// 1. `[writable]` Signer's UerProfile account, created if it does not exist'
+    /// 2. `[]` SYS_VAR_RENT
+    /// 3. `[]` System program
+    ///
+    /// Setting `encryption_key` increments the profile's `encryption_key_version`.
     SetUserProfile {
         name: Option<String>,
         bio: Option<String>,
         lamports_per_message: Option<u64>,
+        encryption_key: Option<PublicKey>,
+    },
+
+    // 0. `[is_signer, writable]` Sender
+    // 1. `[writable]` Receiver
+    // 2. `[writable]` Thread account, created on first contact
+    // 3. `[writable]` Senders Profile account
+    // 4. `[writable]` Receivers Profile account
+    // 5. `[writable]` Message account, created by the program and sized to the message
+    // 6. `[writable]` Jabber Account
+    // 7. `[]` SYS_VAR_RENT
+    // 8. `[]` SYS_VAR_CLOCK
+    // 9. `[]` System program
+    // 10. `[]` Parent message account, if `prev_msg` is set
+    //
+    // `msg` has to be the borsh encoded `MessagePayload` of `kind`, unless `kind` is at least
+    // `Message::CUSTOM_KIND_START`. CTA messages can not be delivered in the past, encrypted
+    // messages have to be sealed to the receiver's current encryption key.
+    //
+    // `prev_msg` is the index of the message in the thread being replied to. `ttl` is the number
+    // of seconds after which anyone can close the message, it has to be positive.
+    //
+    // If the receiver has set `lamports_per_message` on their profile, the sender funds the
+    // message account with that fee on top of its rent exempt balance. The fee is moved to
+    // the receiver.
+    SendMessage {
+        kind: u8,
+        msg: Vec<u8>,
+        ttl: Option<UnixTimestamp>,
+        prev_msg: Option<u32>,
+    },
+
+    // `SendMessage` for other programs, the sender is usually a program derived address signed
+    // with `invoke_signed` and a separate payer funds the accounts. Use `notify` to build it.
+    //
+    // The sender does not need a profile, the thread is then only linked to the receiver. The
+    // payer is recorded on the message and refunded when it is closed.
+    //
+    // 0. `[is_signer]` Sender
+    // 1. `[is_signer, writable]` Payer
+    // 2+. Accounts 1 to 10 of `SendMessage`
+    Notify {
+        kind: u8,
+        msg: Vec<u8>,
+        ttl: Option<UnixTimestamp>,
+        prev_msg: Option<u32>,
+    },
+
+    // 0. `[is_signer]` u1 or u2 of the thread
+    // 1. `[writable]` Thread account
+    SetThreadBan {
+        ban: bool,
+    },
+
+    // Zeroes the message and refunds its lamports to the account that paid for it.
+    //
+    // 0. `[is_signer]` Sender, or anyone once the message has expired
+    // 1. `[writable]` Sender
+    // 2. `[]` Receiver
+    // 3. `[writable]` Message account
+    // 4. `[]` SYS_VAR_CLOCK
+    // 5. `[writable]` Payer, if the message was sent with `Notify` by a separate payer
+    CloseMessage {
+        index: u32,
+    },
+
+    // 0. `[is_signer, writable]` Owner account
+    // 1. `[writable]` Jabber account, allocated by the program if it does not exist
+    // 2. `[]` SYS_VAR_RENT
+    // 3. `[]` System program
+    InitializeJabber,
+
+    // Moves the signer's threads from the unregistered thread list on the Jabber account to
+    // their profile. The threads are a segment of the list following its predecessor, so a
+    // long list can be claimed over several transactions.
+    //
+    // 0. `[is_signer]` User
+    // 1. `[writable]` User's Profile account
+    // 2. `[writable]` Predecessor, the Jabber account to start from the tail of the list or
+    //    a thread on the list
+    // 3+. `[writable]` Thread accounts, in list order after the predecessor
+    ClaimThreads,
+
+    // Adds a reaction or read receipt to a message. Each user can add up to
+    // `Message::MAX_META_PER_USER` entries, adding the same entry twice does nothing.
+    //
+    // 0. `[is_signer]` Sender or receiver of the message
+    // 1. `[]` Sender
+    // 2. `[]` Receiver
+    // 3. `[writable]` Message account
+    // 4. `[]` SYS_VAR_CLOCK
+    AnnotateMessage {
+        index: u32,
+        kind: MetaKind,
     },
 
+    // Replaces the content of a message and records when it was edited. Encrypted messages
+    // can't be edited, their nonce is derived from the message address.
+    //
     // 0. `[is_signer]` Sender
     // 1. `[]` Receiver
-    // 2. `[writable]` Senders Thread account
-    // 3. `[writable]` Receivers Thread account
-    // 4. `[writable]` Senders Profile account
-    // 5. `[]` Receivers Profile account
-    // 6. `[writable]` Message account
-    // 7. `[writable]` Jabber Account
-    // 8. `[]` SYS_VAR_RENT
-    // 9. `[]` SYS_VAR_CLOCK
-    SendMessage {
+    // 2. `[writable]` Message account
+    // 3. `[]` SYS_VAR_CLOCK
+    EditMessage {
+        index: u32,
+        msg: Vec<u8>,
+    },
+
+    // Removes the content of a message for both users, leaving a `Message::RETRACTED_KIND`
+    // tombstone in its place.
+    //
+    // 0. `[is_signer]` Sender
+    // 1. `[]` Receiver
+    // 2. `[writable]` Message account
+    // 3. `[]` SYS_VAR_CLOCK
+    RetractMessage {
+        index: u32,
+    },
+
+    // Updates the signer's chat state on the thread, fields that are not set are left unchanged.
+    //
+    // 0. `[is_signer]` u1 or u2 of the thread
+    // 1. `[writable]` Thread account
+    SetThreadState {
+        last_read: Option<u32>,
+        muted: Option<bool>,
+        archived: Option<bool>,
+    },
+
+    // Sets up a group on an account allocated by the creator in the same transaction, the group
+    // account signs so that nobody else can initialize it. The number of message slots is
+    // determined by the account size, see `Group::capacity_for`. The creator becomes the owner.
+    //
+    // 0. `[is_signer]` Creator
+    // 1. `[is_signer, writable]` Group account, owned by the program
+    // 2. `[]` SYS_VAR_RENT
+    CreateGroup {
+        max_msg_len: u32,
+        post_policy: PostPolicy,
+        admins: Vec<PublicKey>,
+        members: Vec<PublicKey>,
+    },
+
+    // 0. `[is_signer]` Group admin
+    // 1. `[writable]` Group account
+    AddGroupMember {
+        member: PublicKey,
+    },
+
+    // Removes the member along with their roles. Only the owner can remove admins, the owner
+    // has to transfer the group before leaving.
+    //
+    // 0. `[is_signer]` Group admin, or the member leaving the group
+    // 1. `[writable]` Group account
+    RemoveGroupMember {
+        member: PublicKey,
+    },
+
+    // Writes the message to the next slot of the group, overwriting the oldest message once
+    // all slots are used. The group's `PostPolicy` decides who can post.
+    //
+    // 0. `[is_signer]` Group member
+    // 1. `[writable]` Group account
+    // 2. `[]` SYS_VAR_CLOCK
+    PostGroupMessage {
+        kind: u8,
+        msg: Vec<u8>,
+    },
+
+    // 0. `[is_signer]` Group owner to grant admins, group admin to grant posters
+    // 1. `[writable]` Group account
+    GrantGroupRole {
+        user: PublicKey,
+        role: GroupRole,
+    },
+
+    // 0. `[is_signer]` Group owner to revoke admins, group admin to revoke posters
+    // 1. `[writable]` Group account
+    RevokeGroupRole {
+        user: PublicKey,
+        role: GroupRole,
+    },
+
+    // The previous owner stays on as an admin.
+    //
+    // 0. `[is_signer]` Group owner
+    // 1. `[writable]` Group account
+    TransferGroupOwnership {
+        new_owner: PublicKey,
+    },
+
+    // 0. `[is_signer]` Group admin
+    // 1. `[writable]` Group account
+    SetGroupPostPolicy {
+        post_policy: PostPolicy,
+    },
+
+    // 0. `[is_signer, writable]` Publisher
+    // 1. `[writable]` Channel account, created by the program
+    // 2. `[]` SYS_VAR_RENT
+    // 3. `[]` System program
+    CreateChannel {
+        name: String,
+    },
+
+    // 0. `[is_signer, writable]` Subscriber
+    // 1. `[writable]` Channel account
+    // 2. `[writable]` Subscription account, created by the program
+    // 3. `[]` SYS_VAR_RENT
+    // 4. `[]` System program
+    Subscribe,
+
+    // Closes the subscription account and refunds its rent to the subscriber.
+    //
+    // 0. `[is_signer, writable]` Subscriber
+    // 1. `[writable]` Channel account
+    // 2. `[writable]` Subscription account
+    Unsubscribe,
+
+    // Creates a single message account at `Channel::message_address` that all subscribers read.
+    // It can be closed with `CloseMessage`, using the channel as the receiver.
+    //
+    // 0. `[is_signer, writable]` Publisher
+    // 1. `[writable]` Channel account
+    // 2. `[writable]` Message account, created by the program and sized to the message
+    // 3. `[]` SYS_VAR_RENT
+    // 4. `[]` SYS_VAR_CLOCK
+    // 5. `[]` System program
+    Publish {
         kind: u8,
         msg: Vec<u8>,
+        ttl: Option<UnixTimestamp>,
     },
 }
 
@@ -50,502 +278,3239 @@ impl JabberInstruction {
             .map_err(|_| ProgramError::InvalidInstructionData)?;
         let accounts_iter = &mut accounts.iter();
         match instruction {
-            JabberInstruction::SendMessage { kind, msg } if kind >= 10 => {
-                let s_acc = next_account_info(accounts_iter)?;
-                let r_acc = next_account_info(accounts_iter)?;
-                let s_thread_acc = next_account_info(accounts_iter)?;
-                let r_thread_acc = next_account_info(accounts_iter)?;
-                let s_profile_acc = next_account_info(accounts_iter)?;
-                let r_profile_acc = next_account_info(accounts_iter)?;
-                let msg_acc = next_account_info(accounts_iter)?;
-                let jabber_acc = next_account_info(accounts_iter)?;
-                let sysvar_rent_acc = next_account_info(accounts_iter)?;
-                let sysvar_clock_acc = next_account_info(accounts_iter)?;
-
-                if !s_acc.is_signer {
+            JabberInstruction::SendMessage {
+                kind,
+                msg,
+                ttl,
+                prev_msg,
+            } => process_send_message(program_id, accounts_iter, false, kind, msg, ttl, prev_msg),
+            JabberInstruction::Notify {
+                kind,
+                msg,
+                ttl,
+                prev_msg,
+            } => process_send_message(program_id, accounts_iter, true, kind, msg, ttl, prev_msg),
+            JabberInstruction::SetUserProfile {
+                name,
+                bio,
+                lamports_per_message,
+                encryption_key,
+            } => {
+                let user_account = next_account_info(accounts_iter)?;
+                if !user_account.is_signer {
                     return Err(ProgramError::MissingRequiredSignature);
                 }
-                if s_acc.key == r_acc.key {
-                    return Err(ProgramError::InvalidArgument);
-                }
-                if *jabber_acc.key != Jabber::get_account(program_id)? {
-                    info!("Jabber account invalid");
-                    return Err(ProgramError::InvalidAccountData);
+
+                let user_profile_account = next_account_info(accounts_iter)?;
+                let sysvar_rent_acc = next_account_info(accounts_iter)?;
+                let system_program_acc = next_account_info(accounts_iter)?;
+                let (expected_user_profile_pk, bump) =
+                    Profile::find_address(user_account.key, &program_id);
+                if expected_user_profile_pk != *user_profile_account.key {
+                    return Err(JabberError::AccountNotDeterministic.into());
                 }
                 if !rent::check_id(sysvar_rent_acc.key) {
                     info!("Rent system account is not valid");
                     return Err(ProgramError::InvalidAccountData);
                 }
-                if !clock::check_id(sysvar_clock_acc.key) {
-                    info!("Clock system account is not valid");
+                if user_profile_account.data_is_empty() {
+                    let rent = &Rent::from_account_info(sysvar_rent_acc)?;
+                    create_derived_account(
+                        user_account,
+                        user_profile_account,
+                        system_program_acc,
+                        &[Profile::SEED, user_account.key.as_ref(), &[bump]],
+                        rent.minimum_balance(Profile::MIN_SPACE),
+                        Profile::MIN_SPACE,
+                        program_id,
+                    )?;
+                }
+                if user_profile_account.owner != program_id {
                     return Err(ProgramError::InvalidAccountData);
                 }
-                let timestamp = &Clock::from_account_info(sysvar_clock_acc)?.unix_timestamp;
-
-                if s_profile_acc.try_data_len()? < Profile::MIN_SPACE {
-                    return Err(ProgramError::UninitializedAccount);
+                if user_profile_account.try_data_len().unwrap() < Profile::MIN_SPACE {
+                    return Err(ProgramError::AccountDataTooSmall);
                 }
-                let r_profile_exists = r_profile_acc.try_data_len()? >= Profile::MIN_SPACE;
 
-                // Are the profile accounts valid?
-                if *s_profile_acc.key != Profile::create_with_seed(&s_acc.key, program_id)?
-                    || *r_profile_acc.key != Profile::create_with_seed(&r_acc.key, program_id)?
+                let mut user_profile_data = user_profile_account.try_borrow_mut_data()?;
+                let mut out = if Profile::is_initialized(&user_profile_data) {
+                    Profile::unpack(&user_profile_data)?
+                } else {
+                    Profile::default()
+                };
+                if name.as_ref().map_or(0, |i| i.len()) > Profile::MAX_NAME_LEN
+                    || bio.as_ref().map_or(0, |i| i.len()) > Profile::MAX_BIO_LEN
                 {
-                    return Err(JabberError::AccountNotDeterministic.into());
+                    return Err(JabberError::ProfileTooLarge.into());
                 }
-
-                // Are the thread accounts valid?
-                if *s_thread_acc.key
-                    != Thread::create_with_seed(&s_acc.key, &r_acc.key, program_id)?
-                    || *r_thread_acc.key
-                        != Thread::create_with_seed(&r_acc.key, &s_acc.key, program_id)?
-                {
-                    return Err(JabberError::AccountNotDeterministic.into());
+                if let Some(i) = lamports_per_message {
+                    out.lamports_per_message = i;
+                }
+                if let Some(i) = name {
+                    out.name = Some(i);
+                }
+                if let Some(i) = bio {
+                    out.bio = Some(i);
+                }
+                if let Some(i) = encryption_key {
+                    out.encryption_key = Some(i);
+                    out.encryption_key_version = out
+                        .encryption_key_version
+                        .checked_add(1)
+                        .ok_or(ProgramError::InvalidArgument)?;
                 }
 
-                let r_msg_count = match Thread::unpack(&r_thread_acc.try_borrow_data()?) {
-                    Ok(u) => u.msg_count,
-                    _ => 0,
-                };
+                out.pack(&mut user_profile_data)?;
 
-                // Choose the oldest Thread account.
-                let thread_acc = if r_msg_count > 0 {
-                    r_thread_acc
-                } else {
-                    if s_thread_acc.try_data_len()? < Thread::MIN_SPACE {
-                        return Err(ProgramError::AccountDataTooSmall);
-                    }
-                    s_thread_acc
-                };
+                Ok(())
+            }
+            JabberInstruction::SetThreadBan { ban } => {
+                let user_acc = next_account_info(accounts_iter)?;
+                let thread_acc = next_account_info(accounts_iter)?;
 
-                if thread_acc.owner != program_id || s_profile_acc.owner != program_id {
+                if !user_acc.is_signer {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                if thread_acc.owner != program_id {
                     return Err(ProgramError::InvalidAccountData);
                 }
 
-                let rent = &Rent::from_account_info(sysvar_rent_acc)?;
-                if !rent.is_exempt(thread_acc.lamports(), thread_acc.data_len()) {
-                    return Err(JabberError::AccountNotRentExempt.into());
+                let mut thread_data = thread_acc.try_borrow_mut_data()?;
+                let mut thread = Thread::unpack(&thread_data)?;
+                // Each user can only change their own flag.
+                let user_pk = user_acc.key.to_bytes();
+                if thread.u1_pk == user_pk {
+                    thread.u1_ban = ban;
+                } else if thread.u2_pk == user_pk {
+                    thread.u2_ban = ban;
+                } else {
+                    return Err(JabberError::AccountNotAuthorized.into());
+                }
+                thread.pack(&mut thread_data)?;
+
+                Ok(())
+            }
+            JabberInstruction::SetThreadState {
+                last_read,
+                muted,
+                archived,
+            } => {
+                let user_acc = next_account_info(accounts_iter)?;
+                let thread_acc = next_account_info(accounts_iter)?;
+
+                if !user_acc.is_signer {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                if thread_acc.owner != program_id {
+                    return Err(ProgramError::InvalidAccountData);
                 }
 
                 let mut thread_data = thread_acc.try_borrow_mut_data()?;
-                let mut thread = Thread::unpack(&thread_data).map(|mut u| {
-                    if u.msg_count == 0 {
-                        u.msg_count = 1;
+                let mut thread = Thread::unpack(&thread_data)?;
+                let msg_count = thread.msg_count;
+                let user_pk = user_acc.key.to_bytes();
+                let state = if thread.u1_pk == user_pk {
+                    &mut thread.u1_state
+                } else if thread.u2_pk == user_pk {
+                    &mut thread.u2_state
+                } else {
+                    return Err(JabberError::AccountNotAuthorized.into());
+                };
+                if let Some(i) = last_read {
+                    // Message indexes start at 1, `msg_count` is the next free index.
+                    if i >= msg_count {
+                        return Err(JabberError::InvalidMessageIndex.into());
                     }
-                    u
-                })?;
-
-                // Message should be valid
-                if *msg_acc.key
-                    != Message::create_with_seed(
-                        thread.msg_count,
-                        s_acc.key,
-                        r_acc.key,
-                        program_id,
-                    )?
+                    state.last_read = i;
+                }
+                if let Some(i) = muted {
+                    state.muted = i;
+                }
+                if let Some(i) = archived {
+                    state.archived = i;
+                }
+                thread.pack(&mut thread_data)?;
+
+                Ok(())
+            }
+            JabberInstruction::CloseMessage { index } => {
+                let closer_acc = next_account_info(accounts_iter)?;
+                let s_acc = next_account_info(accounts_iter)?;
+                let r_acc = next_account_info(accounts_iter)?;
+                let msg_acc = next_account_info(accounts_iter)?;
+                let sysvar_clock_acc = next_account_info(accounts_iter)?;
+
+                if !closer_acc.is_signer {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                if !clock::check_id(sysvar_clock_acc.key) {
+                    info!("Clock system account is not valid");
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                if *msg_acc.key != Message::find_address(index, s_acc.key, r_acc.key, program_id).0
                 {
                     info!("Message account invalid");
                     return Err(JabberError::AccountNotDeterministic.into());
                 }
+                if msg_acc.owner != program_id {
+                    return Err(ProgramError::InvalidAccountData);
+                }
 
-                // first time?
-                if thread.msg_count == 1 {
-                    thread.u1_pk = s_acc.key.to_bytes();
-                    thread.u2_pk = r_acc.key.to_bytes();
-
-                    let mut s_data = s_profile_acc.try_borrow_mut_data()?;
-                    let mut s = Profile::unpack(&s_data)?;
-                    // Update the thread tail for sender.
-                    thread.prev_thread_u1_pk = s.thread_tail_pk;
-                    s.thread_tail_pk = Some(thread_acc.key.to_bytes());
-                    s.pack(&mut s_data);
-
-                    // Update the thread tail for receiver. We add it to the program
-                    // root account if their profile does not exist.
-                    if r_profile_exists {
-                        let mut r_data = r_profile_acc.try_borrow_mut_data()?;
-                        let mut r = Profile::unpack(&r_data)?;
-                        thread.prev_thread_u2_pk = r.thread_tail_pk;
-                        r.thread_tail_pk = Some(thread_acc.key.to_bytes());
-                        r.pack(&mut r_data);
-                    } else {
-                        // The reciever is not registered, point thread to unregistered users.
-                        let mut jabber_data = jabber_acc.try_borrow_mut_data()?;
-                        let mut jabber = Jabber::unpack(&jabber_data)?;
-                        thread.prev_thread_u2_pk = jabber.unregistered_thread_tail_pk;
-                        jabber.unregistered_thread_tail_pk = Some(thread_acc.key.to_bytes());
-                        jabber.pack(&mut jabber_data);
+                let message = Message::unpack(&msg_acc.try_borrow_data()?)?;
+                if closer_acc.key != s_acc.key {
+                    let now = Clock::from_account_info(sysvar_clock_acc)?.unix_timestamp;
+                    if !message.is_expired(now) {
+                        return Err(JabberError::MessageNotExpired.into());
                     }
                 }
-
-                let message = Message {
-                    kind,
-                    msg,
-                    timestamp: *timestamp,
+                let payer_acc = match message.payer {
+                    Some(payer_pk) => {
+                        let payer_acc = next_account_info(accounts_iter)?;
+                        if payer_acc.key.to_bytes() != payer_pk {
+                            info!("Payer account invalid");
+                            return Err(ProgramError::InvalidArgument);
+                        }
+                        payer_acc
+                    }
+                    None => s_acc,
                 };
-                let mut message_data = msg_acc.try_borrow_mut_data()?;
-                message.pack(&mut message_data);
-                thread.msg_count = thread.msg_count + 1;
-                thread.pack(&mut thread_data);
+
+                // Zero the message and refund whoever paid for it.
+                for b in msg_acc.try_borrow_mut_data()?.iter_mut() {
+                    *b = 0;
+                }
+                let mut msg_lamports = msg_acc.try_borrow_mut_lamports()?;
+                **payer_acc.try_borrow_mut_lamports()? += **msg_lamports;
+                **msg_lamports = 0;
+
                 Ok(())
             }
-            JabberInstruction::SetUserProfile {
-                name,
-                bio,
-                lamports_per_message,
-            } => {
-                let user_account = next_account_info(accounts_iter)?;
-                if !user_account.is_signer {
+            JabberInstruction::AnnotateMessage { index, kind } => {
+                let user_acc = next_account_info(accounts_iter)?;
+                let s_acc = next_account_info(accounts_iter)?;
+                let r_acc = next_account_info(accounts_iter)?;
+                let msg_acc = next_account_info(accounts_iter)?;
+                let sysvar_clock_acc = next_account_info(accounts_iter)?;
+
+                if !user_acc.is_signer {
                     return Err(ProgramError::MissingRequiredSignature);
                 }
+                if !clock::check_id(sysvar_clock_acc.key) {
+                    info!("Clock system account is not valid");
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                if *msg_acc.key != Message::find_address(index, s_acc.key, r_acc.key, program_id).0
+                {
+                    info!("Message account invalid");
+                    return Err(JabberError::AccountNotDeterministic.into());
+                }
+                if msg_acc.owner != program_id {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                let by_sender = if user_acc.key == s_acc.key {
+                    true
+                } else if user_acc.key == r_acc.key {
+                    false
+                } else {
+                    return Err(JabberError::AccountNotAuthorized.into());
+                };
 
-                let user_profile_account = next_account_info(accounts_iter)?;
-                let expected_user_profile_pk =
-                    Profile::create_with_seed(user_account.key, &program_id)?;
-                if expected_user_profile_pk != *user_profile_account.key {
+                let mut msg_data = msg_acc.try_borrow_mut_data()?;
+                let mut message = Message::unpack(&msg_data)?;
+                let user_meta = message.meta.iter().filter(|m| m.by_sender == by_sender);
+                if user_meta.clone().any(|m| m.kind == kind) {
+                    return Ok(());
+                }
+                if user_meta.count() >= Message::MAX_META_PER_USER {
+                    return Err(JabberError::TooManyMessageMeta.into());
+                }
+                message.meta.push(MessageMeta {
+                    by_sender,
+                    kind,
+                    timestamp: Clock::from_account_info(sysvar_clock_acc)?.unix_timestamp,
+                });
+                if message.packed_len()? > msg_data.len() {
+                    return Err(JabberError::MessageTooLarge.into());
+                }
+                message.pack(&mut msg_data)?;
+
+                Ok(())
+            }
+            JabberInstruction::EditMessage { index, msg } => {
+                let s_acc = next_account_info(accounts_iter)?;
+                let r_acc = next_account_info(accounts_iter)?;
+                let msg_acc = next_account_info(accounts_iter)?;
+                let sysvar_clock_acc = next_account_info(accounts_iter)?;
+                let now =
+                    check_sent_message(program_id, index, s_acc, r_acc, msg_acc, sysvar_clock_acc)?;
+
+                let mut msg_data = msg_acc.try_borrow_mut_data()?;
+                let mut message = Message::unpack(&msg_data)?;
+                if message.kind == Message::RETRACTED_KIND {
+                    return Err(JabberError::MessageRetracted.into());
+                }
+                if message.kind == Message::ENCRYPTED_KIND {
+                    info!("Editing would seal the new content with the same nonce");
+                    return Err(JabberError::UnsupportedMessageKind.into());
+                }
+                Message::check_payload(message.kind, &msg, now)?;
+                message.msg = msg;
+                message.edited = Some(now);
+                // The edited message has to leave the space reserved for `meta` free.
+                if message.space()? > msg_data.len() {
+                    return Err(JabberError::MessageTooLarge.into());
+                }
+                message.pack(&mut msg_data)?;
+
+                Ok(())
+            }
+            JabberInstruction::RetractMessage { index } => {
+                let s_acc = next_account_info(accounts_iter)?;
+                let r_acc = next_account_info(accounts_iter)?;
+                let msg_acc = next_account_info(accounts_iter)?;
+                let sysvar_clock_acc = next_account_info(accounts_iter)?;
+                let now =
+                    check_sent_message(program_id, index, s_acc, r_acc, msg_acc, sysvar_clock_acc)?;
+
+                let mut msg_data = msg_acc.try_borrow_mut_data()?;
+                let mut message = Message::unpack(&msg_data)?;
+                message.kind = Message::RETRACTED_KIND;
+                message.msg = vec![];
+                message.edited = Some(now);
+                // Clear the old payload, the tombstone is shorter.
+                for b in msg_data.iter_mut() {
+                    *b = 0;
+                }
+                message.pack(&mut msg_data)?;
+
+                Ok(())
+            }
+            JabberInstruction::InitializeJabber => {
+                let owner_acc = next_account_info(accounts_iter)?;
+                let jabber_acc = next_account_info(accounts_iter)?;
+                let sysvar_rent_acc = next_account_info(accounts_iter)?;
+                let system_program_acc = next_account_info(accounts_iter)?;
+
+                if !owner_acc.is_signer {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                if *owner_acc.key != owner_account::id() {
+                    return Err(JabberError::AccountNotAuthorized.into());
+                }
+                if *jabber_acc.key != Jabber::get_account(program_id)? {
                     return Err(JabberError::AccountNotDeterministic.into());
                 }
-                if user_profile_account.owner != program_id {
+                if !rent::check_id(sysvar_rent_acc.key) {
+                    info!("Rent system account is not valid");
                     return Err(ProgramError::InvalidAccountData);
                 }
-                if user_profile_account.try_data_len().unwrap() < Profile::MIN_SPACE {
+                let rent = &Rent::from_account_info(sysvar_rent_acc)?;
+
+                if jabber_acc.data_is_empty() {
+                    if !system_program::check_id(system_program_acc.key) {
+                        info!("System program account is not valid");
+                        return Err(ProgramError::InvalidAccountData);
+                    }
+                    invoke(
+                        &system_instruction::create_account_with_seed(
+                            owner_acc.key,
+                            jabber_acc.key,
+                            owner_acc.key,
+                            Jabber::SEED,
+                            rent.minimum_balance(Jabber::SPACE),
+                            Jabber::SPACE as u64,
+                            program_id,
+                        ),
+                        &[
+                            owner_acc.clone(),
+                            jabber_acc.clone(),
+                            system_program_acc.clone(),
+                        ],
+                    )?;
+                }
+
+                if jabber_acc.owner != program_id {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                if jabber_acc.data_len() < Jabber::SPACE {
                     return Err(ProgramError::AccountDataTooSmall);
                 }
+                if !rent.is_exempt(jabber_acc.lamports(), jabber_acc.data_len()) {
+                    return Err(JabberError::AccountNotRentExempt.into());
+                }
 
-                let decoded = Profile::unpack(&user_profile_account.try_borrow_data()?);
+                let mut jabber_data = jabber_acc.try_borrow_mut_data()?;
+                if Jabber::is_initialized(&jabber_data) {
+                    return Err(JabberError::AccountAlreadyInitialized.into());
+                }
+                Jabber {
+                    unregistered_thread_tail_pk: None,
+                }
+                .pack(&mut jabber_data)?;
+
+                Ok(())
+            }
+            JabberInstruction::ClaimThreads => {
+                let user_acc = next_account_info(accounts_iter)?;
+                let profile_acc = next_account_info(accounts_iter)?;
+                let prev_acc = next_account_info(accounts_iter)?;
+
+                if !user_acc.is_signer {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                if *profile_acc.key != Profile::find_address(user_acc.key, program_id).0 {
+                    return Err(JabberError::AccountNotDeterministic.into());
+                }
+                if profile_acc.owner != program_id {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                if profile_acc.try_data_len()? < Profile::MIN_SPACE {
+                    return Err(ProgramError::UninitializedAccount);
+                }
+                if prev_acc.owner != program_id {
+                    return Err(ProgramError::InvalidAccountData);
+                }
 
-                let mut out = match decoded {
-                    Ok(u) => u,
-                    Err(_) => Profile::default(),
+                let mut profile_data = profile_acc.try_borrow_mut_data()?;
+                let mut profile = Profile::unpack(&profile_data)?;
+
+                // The Jabber account holds the tail of the list, None if the segment starts
+                // after a thread.
+                let mut jabber = None;
+                // The last thread kept in the list.
+                let mut kept: Option<(&AccountInfo, Thread)> = None;
+                let mut next_pk = if *prev_acc.key == Jabber::get_account(program_id)? {
+                    let j = Jabber::unpack(&prev_acc.try_borrow_data()?)?;
+                    let next_pk = j.unregistered_thread_tail_pk;
+                    jabber = Some(j);
+                    next_pk
+                } else {
+                    let thread = Thread::unpack(&prev_acc.try_borrow_data()?)?;
+                    if !thread.unregistered {
+                        info!("Thread is not on the unregistered thread list");
+                        return Err(ProgramError::InvalidArgument);
+                    }
+                    let next_pk = thread.prev_thread_u2_pk;
+                    kept = Some((prev_acc, thread));
+                    next_pk
                 };
-                if let Some(i) = lamports_per_message {
-                    out.lamports_per_message = i;
+
+                let user_pk = user_acc.key.to_bytes();
+                let mut claimed = Vec::new();
+                for thread_acc in accounts_iter {
+                    if Some(thread_acc.key.to_bytes()) != next_pk {
+                        info!("Thread is not next in the unregistered thread list");
+                        return Err(ProgramError::InvalidArgument);
+                    }
+                    if thread_acc.owner != program_id {
+                        return Err(ProgramError::InvalidAccountData);
+                    }
+                    let thread = Thread::unpack(&thread_acc.try_borrow_data()?)?;
+                    next_pk = thread.prev_thread_u2_pk;
+
+                    if thread.u2_pk == user_pk {
+                        // Unlink the thread from the unregistered thread list.
+                        if let Some((_, ref mut t)) = kept {
+                            t.prev_thread_u2_pk = next_pk;
+                        } else if let Some(ref mut j) = jabber {
+                            j.unregistered_thread_tail_pk = next_pk;
+                        }
+                        claimed.push((thread_acc, thread));
+                    } else {
+                        if let Some((acc, t)) = kept.take() {
+                            t.pack(&mut acc.try_borrow_mut_data()?)?;
+                        }
+                        kept = Some((thread_acc, thread));
+                    }
                 }
-                if let Some(i) = name {
-                    out.name = Some(i);
+                if let Some((acc, t)) = kept {
+                    t.pack(&mut acc.try_borrow_mut_data()?)?;
                 }
-                if let Some(i) = bio {
-                    out.bio = Some(i);
+                if let Some(j) = jabber {
+                    j.pack(&mut prev_acc.try_borrow_mut_data()?)?;
                 }
 
-                let mut user_profile_data = user_profile_account.try_borrow_mut_data()?;
-                out.pack(&mut user_profile_data);
+                // Link the oldest thread first so that the latest one ends up as the tail.
+                for (acc, mut thread) in claimed.into_iter().rev() {
+                    thread.prev_thread_u2_pk = profile.thread_tail_pk;
+                    thread.unregistered = false;
+                    profile.thread_tail_pk = Some(acc.key.to_bytes());
+                    thread.pack(&mut acc.try_borrow_mut_data()?)?;
+                }
+
+                profile.pack(&mut profile_data)?;
+
+                Ok(())
+            }
+            JabberInstruction::CreateGroup {
+                max_msg_len,
+                post_policy,
+                admins,
+                members,
+            } => {
+                let creator_acc = next_account_info(accounts_iter)?;
+                let group_acc = next_account_info(accounts_iter)?;
+                let sysvar_rent_acc = next_account_info(accounts_iter)?;
+
+                if !creator_acc.is_signer || !group_acc.is_signer {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                if !rent::check_id(sysvar_rent_acc.key) {
+                    info!("Rent system account is not valid");
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                if group_acc.owner != program_id {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                let rent = &Rent::from_account_info(sysvar_rent_acc)?;
+                if !rent.is_exempt(group_acc.lamports(), group_acc.data_len()) {
+                    return Err(JabberError::AccountNotRentExempt.into());
+                }
+                let capacity = Group::capacity_for(group_acc.data_len(), max_msg_len);
+                if capacity == 0 || capacity > u32::MAX as usize {
+                    return Err(ProgramError::AccountDataTooSmall);
+                }
+
+                if admins.len() > Group::MAX_ADMINS || members.len() > Group::MAX_MEMBERS {
+                    return Err(JabberError::GroupMemberLimit.into());
+                }
+
+                let mut group_data = group_acc.try_borrow_mut_data()?;
+                if Group::is_initialized(&group_data) {
+                    return Err(JabberError::AccountAlreadyInitialized.into());
+                }
+                Group {
+                    creator: creator_acc.key.to_bytes(),
+                    owner: creator_acc.key.to_bytes(),
+                    admins,
+                    posters: vec![],
+                    members,
+                    post_policy,
+                    max_msg_len,
+                    capacity: capacity as u32,
+                    msg_count: 0,
+                }
+                .pack(&mut group_data[..Group::SPACE])?;
+
+                Ok(())
+            }
+            JabberInstruction::AddGroupMember { member } => {
+                let admin_acc = next_account_info(accounts_iter)?;
+                let group_acc = next_account_info(accounts_iter)?;
+                let mut group_data = group_acc.try_borrow_mut_data()?;
+                let mut group = unpack_group(program_id, admin_acc, group_acc, &group_data)?;
+                if !group.is_admin(&admin_acc.key.to_bytes()) {
+                    return Err(JabberError::NotGroupAdmin.into());
+                }
+                if group.is_member(&member) {
+                    return Ok(());
+                }
+                if group.members.len() >= Group::MAX_MEMBERS {
+                    return Err(JabberError::GroupMemberLimit.into());
+                }
+                group.members.push(member);
+                group.pack(&mut group_data[..Group::SPACE])?;
+
+                Ok(())
+            }
+            JabberInstruction::RemoveGroupMember { member } => {
+                let user_acc = next_account_info(accounts_iter)?;
+                let group_acc = next_account_info(accounts_iter)?;
+                let mut group_data = group_acc.try_borrow_mut_data()?;
+                let mut group = unpack_group(program_id, user_acc, group_acc, &group_data)?;
+                let user_pk = user_acc.key.to_bytes();
+                if member == group.owner {
+                    info!("The owner has to transfer the group first");
+                    return Err(ProgramError::InvalidArgument);
+                }
+                if user_pk != member {
+                    if !group.is_admin(&user_pk) {
+                        return Err(JabberError::NotGroupAdmin.into());
+                    }
+                    if group.admins.contains(&member) && user_pk != group.owner {
+                        return Err(JabberError::NotGroupOwner.into());
+                    }
+                }
+                group.members.retain(|pk| *pk != member);
+                group.posters.retain(|pk| *pk != member);
+                group.admins.retain(|pk| *pk != member);
+                group.pack(&mut group_data[..Group::SPACE])?;
+
+                Ok(())
+            }
+            JabberInstruction::PostGroupMessage { kind, msg } => {
+                let sender_acc = next_account_info(accounts_iter)?;
+                let group_acc = next_account_info(accounts_iter)?;
+                let sysvar_clock_acc = next_account_info(accounts_iter)?;
+
+                if !sender_acc.is_signer {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                if group_acc.owner != program_id {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                if !clock::check_id(sysvar_clock_acc.key) {
+                    info!("Clock system account is not valid");
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                let timestamp = Clock::from_account_info(sysvar_clock_acc)?.unix_timestamp;
+                Message::check_payload(kind, &msg, timestamp)?;
+
+                let mut group_data = group_acc.try_borrow_mut_data()?;
+                let mut group = Group::unpack(
+                    group_data
+                        .get(..Group::SPACE)
+                        .ok_or(ProgramError::AccountDataTooSmall)?,
+                )?;
+                let sender_pk = sender_acc.key.to_bytes();
+                group.check_post(&sender_pk)?;
+                if msg.len() > group.max_msg_len as usize {
+                    return Err(JabberError::MessageTooLarge.into());
+                }
+
+                let slot = &mut group_data[group.slot_range(group.msg_count)];
+                for b in slot.iter_mut() {
+                    *b = 0;
+                }
+                let encoded = GroupMessage {
+                    sender: sender_pk,
+                    kind,
+                    timestamp,
+                    msg,
+                }
+                .try_to_vec()
+                .map_err(|_| ProgramError::InvalidArgument)?;
+                slot[..encoded.len()].copy_from_slice(&encoded);
+                group.msg_count += 1;
+                group.pack(&mut group_data[..Group::SPACE])?;
+
+                Ok(())
+            }
+            JabberInstruction::GrantGroupRole { user, role } => {
+                let signer_acc = next_account_info(accounts_iter)?;
+                let group_acc = next_account_info(accounts_iter)?;
+                let mut group_data = group_acc.try_borrow_mut_data()?;
+                let mut group = unpack_group(program_id, signer_acc, group_acc, &group_data)?;
+                check_group_role_authority(&group, &signer_acc.key.to_bytes(), role)?;
+
+                let (users, max_users) = group.role_list(role);
+                if !users.contains(&user) {
+                    if users.len() >= max_users {
+                        return Err(JabberError::GroupMemberLimit.into());
+                    }
+                    users.push(user);
+                }
+                group.pack(&mut group_data[..Group::SPACE])?;
+
+                Ok(())
+            }
+            JabberInstruction::RevokeGroupRole { user, role } => {
+                let signer_acc = next_account_info(accounts_iter)?;
+                let group_acc = next_account_info(accounts_iter)?;
+                let mut group_data = group_acc.try_borrow_mut_data()?;
+                let mut group = unpack_group(program_id, signer_acc, group_acc, &group_data)?;
+                check_group_role_authority(&group, &signer_acc.key.to_bytes(), role)?;
+
+                group.role_list(role).0.retain(|pk| *pk != user);
+                group.pack(&mut group_data[..Group::SPACE])?;
+
+                Ok(())
+            }
+            JabberInstruction::TransferGroupOwnership { new_owner } => {
+                let owner_acc = next_account_info(accounts_iter)?;
+                let group_acc = next_account_info(accounts_iter)?;
+                let mut group_data = group_acc.try_borrow_mut_data()?;
+                let mut group = unpack_group(program_id, owner_acc, group_acc, &group_data)?;
+                if owner_acc.key.to_bytes() != group.owner {
+                    return Err(JabberError::NotGroupOwner.into());
+                }
+
+                // The new owner is an admin by being the owner.
+                group.admins.retain(|pk| *pk != new_owner);
+                if group.admins.len() >= Group::MAX_ADMINS {
+                    return Err(JabberError::GroupMemberLimit.into());
+                }
+                group.admins.push(group.owner);
+                group.owner = new_owner;
+                group.pack(&mut group_data[..Group::SPACE])?;
+
+                Ok(())
+            }
+            JabberInstruction::SetGroupPostPolicy { post_policy } => {
+                let admin_acc = next_account_info(accounts_iter)?;
+                let group_acc = next_account_info(accounts_iter)?;
+                let mut group_data = group_acc.try_borrow_mut_data()?;
+                let mut group = unpack_group(program_id, admin_acc, group_acc, &group_data)?;
+                if !group.is_admin(&admin_acc.key.to_bytes()) {
+                    return Err(JabberError::NotGroupAdmin.into());
+                }
+
+                group.post_policy = post_policy;
+                group.pack(&mut group_data[..Group::SPACE])?;
+
+                Ok(())
+            }
+            JabberInstruction::CreateChannel { name } => {
+                let publisher_acc = next_account_info(accounts_iter)?;
+                let channel_acc = next_account_info(accounts_iter)?;
+                let sysvar_rent_acc = next_account_info(accounts_iter)?;
+                let system_program_acc = next_account_info(accounts_iter)?;
+
+                if !publisher_acc.is_signer {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                if name.is_empty() || name.len() > Channel::MAX_NAME_LEN {
+                    return Err(JabberError::InvalidChannelName.into());
+                }
+                let (channel_pk, bump) =
+                    Channel::find_address(publisher_acc.key, &name, program_id);
+                if *channel_acc.key != channel_pk {
+                    return Err(JabberError::AccountNotDeterministic.into());
+                }
+                if !rent::check_id(sysvar_rent_acc.key) {
+                    info!("Rent system account is not valid");
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                let rent = &Rent::from_account_info(sysvar_rent_acc)?;
+                if channel_acc.data_is_empty() {
+                    create_derived_account(
+                        publisher_acc,
+                        channel_acc,
+                        system_program_acc,
+                        &[
+                            Channel::SEED,
+                            publisher_acc.key.as_ref(),
+                            name.as_bytes(),
+                            &[bump],
+                        ],
+                        rent.minimum_balance(Channel::SPACE),
+                        Channel::SPACE,
+                        program_id,
+                    )?;
+                }
+                if channel_acc.owner != program_id {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                if channel_acc.data_len() < Channel::SPACE {
+                    return Err(ProgramError::AccountDataTooSmall);
+                }
+
+                let mut channel_data = channel_acc.try_borrow_mut_data()?;
+                if Channel::is_initialized(&channel_data) {
+                    return Err(JabberError::AccountAlreadyInitialized.into());
+                }
+                Channel {
+                    publisher: publisher_acc.key.to_bytes(),
+                    name,
+                    subscriber_count: 0,
+                    msg_count: 1,
+                }
+                .pack(&mut channel_data)?;
+
+                Ok(())
+            }
+            JabberInstruction::Subscribe => {
+                let subscriber_acc = next_account_info(accounts_iter)?;
+                let channel_acc = next_account_info(accounts_iter)?;
+                let subscription_acc = next_account_info(accounts_iter)?;
+                let sysvar_rent_acc = next_account_info(accounts_iter)?;
+                let system_program_acc = next_account_info(accounts_iter)?;
+
+                if !subscriber_acc.is_signer {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                if channel_acc.owner != program_id {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                let mut channel = Channel::unpack(&channel_acc.try_borrow_data()?)?;
+                let (subscription_pk, bump) =
+                    Subscription::find_address(channel_acc.key, subscriber_acc.key, program_id);
+                if *subscription_acc.key != subscription_pk {
+                    return Err(JabberError::AccountNotDeterministic.into());
+                }
+                if !rent::check_id(sysvar_rent_acc.key) {
+                    info!("Rent system account is not valid");
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                let rent = &Rent::from_account_info(sysvar_rent_acc)?;
+                if subscription_acc.data_is_empty() {
+                    create_derived_account(
+                        subscriber_acc,
+                        subscription_acc,
+                        system_program_acc,
+                        &[
+                            Subscription::SEED,
+                            channel_acc.key.as_ref(),
+                            subscriber_acc.key.as_ref(),
+                            &[bump],
+                        ],
+                        rent.minimum_balance(Subscription::SPACE),
+                        Subscription::SPACE,
+                        program_id,
+                    )?;
+                }
+                if subscription_acc.owner != program_id {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                if subscription_acc.data_len() < Subscription::SPACE {
+                    return Err(ProgramError::AccountDataTooSmall);
+                }
+
+                let mut subscription_data = subscription_acc.try_borrow_mut_data()?;
+                if Subscription::is_initialized(&subscription_data) {
+                    return Err(JabberError::AccountAlreadyInitialized.into());
+                }
+                Subscription {
+                    channel: channel_acc.key.to_bytes(),
+                    subscriber: subscriber_acc.key.to_bytes(),
+                    since: channel.msg_count,
+                }
+                .pack(&mut subscription_data)?;
+                channel.subscriber_count += 1;
+                channel.pack(&mut channel_acc.try_borrow_mut_data()?)?;
+
+                Ok(())
+            }
+            JabberInstruction::Unsubscribe => {
+                let subscriber_acc = next_account_info(accounts_iter)?;
+                let channel_acc = next_account_info(accounts_iter)?;
+                let subscription_acc = next_account_info(accounts_iter)?;
+
+                if !subscriber_acc.is_signer {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                if channel_acc.owner != program_id || subscription_acc.owner != program_id {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                if *subscription_acc.key
+                    != Subscription::find_address(channel_acc.key, subscriber_acc.key, program_id).0
+                {
+                    return Err(JabberError::AccountNotDeterministic.into());
+                }
+
+                let mut subscription_data = subscription_acc.try_borrow_mut_data()?;
+                Subscription::unpack(&subscription_data)?;
+                let mut channel_data = channel_acc.try_borrow_mut_data()?;
+                let mut channel = Channel::unpack(&channel_data)?;
+                channel.subscriber_count = channel.subscriber_count.saturating_sub(1);
+                channel.pack(&mut channel_data)?;
+
+                for b in subscription_data.iter_mut() {
+                    *b = 0;
+                }
+                let mut subscription_lamports = subscription_acc.try_borrow_mut_lamports()?;
+                **subscriber_acc.try_borrow_mut_lamports()? += **subscription_lamports;
+                **subscription_lamports = 0;
+
+                Ok(())
+            }
+            JabberInstruction::Publish { kind, msg, ttl } => {
+                let publisher_acc = next_account_info(accounts_iter)?;
+                let channel_acc = next_account_info(accounts_iter)?;
+                let msg_acc = next_account_info(accounts_iter)?;
+                let sysvar_rent_acc = next_account_info(accounts_iter)?;
+                let sysvar_clock_acc = next_account_info(accounts_iter)?;
+                let system_program_acc = next_account_info(accounts_iter)?;
+
+                if !publisher_acc.is_signer {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                if channel_acc.owner != program_id {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                if !rent::check_id(sysvar_rent_acc.key) {
+                    info!("Rent system account is not valid");
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                if !clock::check_id(sysvar_clock_acc.key) {
+                    info!("Clock system account is not valid");
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                let timestamp = Clock::from_account_info(sysvar_clock_acc)?.unix_timestamp;
+                let rent = &Rent::from_account_info(sysvar_rent_acc)?;
+                Message::check_payload(kind, &msg, timestamp)?;
+                Message::check_ttl(ttl)?;
+
+                let mut channel_data = channel_acc.try_borrow_mut_data()?;
+                let mut channel = Channel::unpack(&channel_data)?;
+                if channel.publisher != publisher_acc.key.to_bytes() {
+                    return Err(JabberError::AccountNotAuthorized.into());
+                }
+                let (msg_pk, msg_bump) = Channel::message_address(
+                    channel.msg_count,
+                    publisher_acc.key,
+                    channel_acc.key,
+                    program_id,
+                );
+                if *msg_acc.key != msg_pk {
+                    info!("Message account invalid");
+                    return Err(JabberError::AccountNotDeterministic.into());
+                }
+
+                // Published messages are not edited or annotated, no space is reserved for it.
+                let message = Message {
+                    kind,
+                    msg,
+                    timestamp,
+                    ttl,
+                    edited: None,
+                    prev_msg: None,
+                    payer: None,
+                    meta: vec![],
+                };
+                let space = message.packed_len()?;
+                if msg_acc.data_is_empty() {
+                    create_derived_account(
+                        publisher_acc,
+                        msg_acc,
+                        system_program_acc,
+                        &[
+                            Message::SEED,
+                            publisher_acc.key.as_ref(),
+                            channel_acc.key.as_ref(),
+                            &channel.msg_count.to_le_bytes(),
+                            &[msg_bump],
+                        ],
+                        rent.minimum_balance(space),
+                        space,
+                        program_id,
+                    )?;
+                }
+                if msg_acc.owner != program_id {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                if msg_acc.data_len() < space {
+                    return Err(JabberError::MessageTooLarge.into());
+                }
+
+                message.pack(&mut msg_acc.try_borrow_mut_data()?)?;
+                channel.msg_count += 1;
+                channel.pack(&mut channel_data)?;
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Builds a `SetUserProfile` instruction for `user_pk`. Fields left as `None` are not changed.
+pub fn set_user_profile(
+    program_id: &Pubkey,
+    user_pk: &Pubkey,
+    name: Option<String>,
+    bio: Option<String>,
+    lamports_per_message: Option<u64>,
+    encryption_key: Option<PublicKey>,
+) -> Result<Instruction, ProgramError> {
+    let data = JabberInstruction::SetUserProfile {
+        name,
+        bio,
+        lamports_per_message,
+        encryption_key,
+    }
+    .try_to_vec()
+    .map_err(|_| ProgramError::InvalidArgument)?;
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*user_pk, true),
+            AccountMeta::new(Profile::find_address(user_pk, program_id).0, false),
+            AccountMeta::new_readonly(rent::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+        data,
+    })
+}
+
+/// Builds a `SendMessage` instruction from `sender_pk` to `receiver_pk`. `msg_index` is the
+/// `msg_count` of their thread, or 1 if the thread does not exist yet.
+///
+/// `prev_msg` is the index and sender of the message being replied to.
+#[allow(clippy::too_many_arguments)]
+pub fn send_message(
+    program_id: &Pubkey,
+    sender_pk: &Pubkey,
+    receiver_pk: &Pubkey,
+    msg_index: u32,
+    kind: u8,
+    msg: Vec<u8>,
+    ttl: Option<UnixTimestamp>,
+    prev_msg: Option<(u32, &Pubkey)>,
+) -> Result<Instruction, ProgramError> {
+    let data = JabberInstruction::SendMessage {
+        kind,
+        msg,
+        ttl,
+        prev_msg: prev_msg.map(|(index, _)| index),
+    }
+    .try_to_vec()
+    .map_err(|_| ProgramError::InvalidArgument)?;
+    let mut accounts = vec![AccountMeta::new(*sender_pk, true)];
+    accounts.extend(message_account_metas(
+        program_id,
+        sender_pk,
+        receiver_pk,
+        msg_index,
+        prev_msg,
+    )?);
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Builds a `Notify` instruction from `sender_pk` to `receiver_pk`. `msg_index` is the
+/// `msg_count` of their thread, or 1 if the thread does not exist yet.
+///
+/// `prev_msg` is the index and sender of the message being replied to.
+#[allow(clippy::too_many_arguments)]
+pub fn notify(
+    program_id: &Pubkey,
+    sender_pk: &Pubkey,
+    payer_pk: &Pubkey,
+    receiver_pk: &Pubkey,
+    msg_index: u32,
+    kind: u8,
+    msg: Vec<u8>,
+    ttl: Option<UnixTimestamp>,
+    prev_msg: Option<(u32, &Pubkey)>,
+) -> Result<Instruction, ProgramError> {
+    let data = JabberInstruction::Notify {
+        kind,
+        msg,
+        ttl,
+        prev_msg: prev_msg.map(|(index, _)| index),
+    }
+    .try_to_vec()
+    .map_err(|_| ProgramError::InvalidArgument)?;
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*sender_pk, true),
+        AccountMeta::new(*payer_pk, true),
+    ];
+    accounts.extend(message_account_metas(
+        program_id,
+        sender_pk,
+        receiver_pk,
+        msg_index,
+        prev_msg,
+    )?);
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Accounts 1 to 10 of `SendMessage`, the parent message only when replying.
+fn message_account_metas(
+    program_id: &Pubkey,
+    sender_pk: &Pubkey,
+    receiver_pk: &Pubkey,
+    msg_index: u32,
+    prev_msg: Option<(u32, &Pubkey)>,
+) -> Result<Vec<AccountMeta>, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new(*receiver_pk, false),
+        AccountMeta::new(
+            Thread::find_address(sender_pk, receiver_pk, program_id).0,
+            false,
+        ),
+        AccountMeta::new(Profile::find_address(sender_pk, program_id).0, false),
+        AccountMeta::new(Profile::find_address(receiver_pk, program_id).0, false),
+        AccountMeta::new(
+            Message::find_address(msg_index, sender_pk, receiver_pk, program_id).0,
+            false,
+        ),
+        AccountMeta::new(Jabber::get_account(program_id)?, false),
+        AccountMeta::new_readonly(rent::id(), false),
+        AccountMeta::new_readonly(clock::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    if let Some((index, parent_sender_pk)) = prev_msg {
+        let parent_receiver_pk = if parent_sender_pk == sender_pk {
+            receiver_pk
+        } else {
+            sender_pk
+        };
+        accounts.push(AccountMeta::new_readonly(
+            Message::find_address(index, parent_sender_pk, parent_receiver_pk, program_id).0,
+            false,
+        ));
+    }
+    Ok(accounts)
+}
+
+/// Creates a program derived account, paid for by `payer`.
+fn create_derived_account<'a>(
+    payer: &AccountInfo<'a>,
+    account: &AccountInfo<'a>,
+    system_program_acc: &AccountInfo<'a>,
+    signer_seeds: &[&[u8]],
+    lamports: u64,
+    space: usize,
+    program_id: &Pubkey,
+) -> ProgramResult {
+    if !system_program::check_id(system_program_acc.key) {
+        info!("System program account is not valid");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let instructions = derived_account_instructions(
+        payer.key,
+        account.key,
+        account.lamports(),
+        lamports,
+        space,
+        program_id,
+    );
+    for instruction in instructions.iter() {
+        invoke_signed(
+            instruction,
+            &[payer.clone(), account.clone(), system_program_acc.clone()],
+            &[signer_seeds],
+        )?;
+    }
+    Ok(())
+}
+
+/// System program instructions that create `account_pk` with `lamports` and `space`.
+///
+/// Derived addresses are known in advance, and the system program refuses to create an account
+/// that already holds lamports. Anyone could block an address by funding it, so a funded
+/// account is topped up, allocated and assigned instead.
+fn derived_account_instructions(
+    payer_pk: &Pubkey,
+    account_pk: &Pubkey,
+    account_lamports: u64,
+    lamports: u64,
+    space: usize,
+    program_id: &Pubkey,
+) -> Vec<Instruction> {
+    if account_lamports == 0 {
+        return vec![system_instruction::create_account(
+            payer_pk,
+            account_pk,
+            lamports,
+            space as u64,
+            program_id,
+        )];
+    }
+    let mut instructions = vec![];
+    if lamports > account_lamports {
+        instructions.push(system_instruction::transfer(
+            payer_pk,
+            account_pk,
+            lamports - account_lamports,
+        ));
+    }
+    instructions.push(system_instruction::allocate(account_pk, space as u64));
+    instructions.push(system_instruction::assign(account_pk, program_id));
+    instructions
+}
+
+/// Sends a message from the first account, paid for by the next account if `with_payer` is set.
+fn process_send_message<'a, 'b: 'a>(
+    program_id: &Pubkey,
+    accounts_iter: &mut std::slice::Iter<'a, AccountInfo<'b>>,
+    with_payer: bool,
+    kind: u8,
+    msg: Vec<u8>,
+    ttl: Option<UnixTimestamp>,
+    prev_msg: Option<u32>,
+) -> ProgramResult {
+    let s_acc = next_account_info(accounts_iter)?;
+    let payer_acc = if with_payer {
+        next_account_info(accounts_iter)?
+    } else {
+        s_acc
+    };
+    let r_acc = next_account_info(accounts_iter)?;
+    let thread_acc = next_account_info(accounts_iter)?;
+    let s_profile_acc = next_account_info(accounts_iter)?;
+    let r_profile_acc = next_account_info(accounts_iter)?;
+    let msg_acc = next_account_info(accounts_iter)?;
+    let jabber_acc = next_account_info(accounts_iter)?;
+    let sysvar_rent_acc = next_account_info(accounts_iter)?;
+    let sysvar_clock_acc = next_account_info(accounts_iter)?;
+    let system_program_acc = next_account_info(accounts_iter)?;
+
+    if !s_acc.is_signer || !payer_acc.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if s_acc.key == r_acc.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if *jabber_acc.key != Jabber::get_account(program_id)? || jabber_acc.owner != program_id {
+        info!("Jabber account invalid");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    if !rent::check_id(sysvar_rent_acc.key) {
+        info!("Rent system account is not valid");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    if !clock::check_id(sysvar_clock_acc.key) {
+        info!("Clock system account is not valid");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let timestamp = &Clock::from_account_info(sysvar_clock_acc)?.unix_timestamp;
+    let rent = &Rent::from_account_info(sysvar_rent_acc)?;
+    Message::check_payload(kind, &msg, *timestamp)?;
+    Message::check_ttl(ttl)?;
+
+    // Are the profile accounts valid?
+    if *s_profile_acc.key != Profile::find_address(s_acc.key, program_id).0
+        || *r_profile_acc.key != Profile::find_address(r_acc.key, program_id).0
+    {
+        return Err(JabberError::AccountNotDeterministic.into());
+    }
+    // Programs notifying users through `Notify` may not have a profile.
+    let s_profile_exists = s_profile_acc.owner == program_id
+        && s_profile_acc.try_data_len()? >= Profile::MIN_SPACE
+        && Profile::is_initialized(&s_profile_acc.try_borrow_data()?);
+    if !s_profile_exists && !with_payer {
+        return Err(ProgramError::UninitializedAccount);
+    }
+    let r_profile_exists = r_profile_acc.owner == program_id
+        && r_profile_acc.try_data_len()? >= Profile::MIN_SPACE
+        && Profile::is_initialized(&r_profile_acc.try_borrow_data()?);
+
+    // Is the thread account valid?
+    let (thread_pk, thread_bump) = Thread::find_address(s_acc.key, r_acc.key, program_id);
+    if *thread_acc.key != thread_pk {
+        return Err(JabberError::AccountNotDeterministic.into());
+    }
+    // Create the thread on first contact.
+    if thread_acc.data_is_empty() {
+        let (u1_pk, u2_pk) = Thread::ordered_pks(s_acc.key, r_acc.key);
+        create_derived_account(
+            payer_acc,
+            thread_acc,
+            system_program_acc,
+            &[Thread::SEED, u1_pk.as_ref(), u2_pk.as_ref(), &[thread_bump]],
+            rent.minimum_balance(Thread::MIN_SPACE),
+            Thread::MIN_SPACE,
+            program_id,
+        )?;
+    }
+    if thread_acc.owner != program_id {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    if thread_acc.data_len() < Thread::MIN_SPACE {
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+    if !rent.is_exempt(thread_acc.lamports(), thread_acc.data_len()) {
+        return Err(JabberError::AccountNotRentExempt.into());
+    }
+
+    let mut thread_data = thread_acc.try_borrow_mut_data()?;
+    let mut thread = if Thread::is_initialized(&thread_data) {
+        Thread::unpack(&thread_data)?
+    } else {
+        Thread {
+            msg_count: 1,
+            prev_thread_u1_pk: None,
+            prev_thread_u2_pk: None,
+            unregistered: false,
+            u1_pk: s_acc.key.to_bytes(),
+            u2_pk: r_acc.key.to_bytes(),
+            u1_ban: false,
+            u2_ban: false,
+            u1_state: ThreadState::default(),
+            u2_state: ThreadState::default(),
+        }
+    };
+
+    let s_pk = s_acc.key.to_bytes();
+    if (thread.u1_pk == s_pk && thread.u2_ban) || (thread.u2_pk == s_pk && thread.u1_ban) {
+        info!("Sender is banned from the thread");
+        return Err(JabberError::ThreadBanned.into());
+    }
+
+    // Message should be valid
+    let (msg_pk, msg_bump) =
+        Message::find_address(thread.msg_count, s_acc.key, r_acc.key, program_id);
+    if *msg_acc.key != msg_pk {
+        info!("Message account invalid");
+        return Err(JabberError::AccountNotDeterministic.into());
+    }
+
+    // The parent message must be an earlier message from either side of the thread.
+    let prev_msg = match prev_msg {
+        Some(index) => {
+            let parent_acc = next_account_info(accounts_iter)?;
+            if index == 0
+                || index >= thread.msg_count
+                || (*parent_acc.key
+                    != Message::find_address(index, s_acc.key, r_acc.key, program_id).0
+                    && *parent_acc.key
+                        != Message::find_address(index, r_acc.key, s_acc.key, program_id).0)
+            {
+                info!("Parent message is not in the thread");
+                return Err(JabberError::InvalidParentMessage.into());
+            }
+            // A closed parent is zeroed, and removed once the refund is final.
+            if parent_acc.owner != program_id
+                || !Message::is_initialized(&parent_acc.try_borrow_data()?)
+            {
+                info!("Parent message has been closed");
+                return Err(JabberError::InvalidParentMessage.into());
+            }
+            Message::unpack(&parent_acc.try_borrow_data()?)?;
+            Some(parent_acc.key.to_bytes())
+        }
+        None => None,
+    };
+
+    let r_profile = if r_profile_exists {
+        Some(Profile::unpack(&r_profile_acc.try_borrow_data()?)?)
+    } else {
+        None
+    };
+    let fee = r_profile.as_ref().map_or(0, |p| p.lamports_per_message);
+    if kind == Message::ENCRYPTED_KIND {
+        let key_version = r_profile.as_ref().map_or(0, |p| p.encryption_key_version);
+        match MessagePayload::unpack(kind, &msg)? {
+            MessagePayload::Encrypted(e) if e.key_version == key_version => {}
+            _ => return Err(JabberError::EncryptionKeyMismatch.into()),
+        }
+    }
+
+    let message = Message {
+        kind,
+        msg,
+        timestamp: *timestamp,
+        ttl,
+        edited: None,
+        prev_msg,
+        payer: if payer_acc.key != s_acc.key {
+            Some(payer_acc.key.to_bytes())
+        } else {
+            None
+        },
+        meta: vec![],
+    };
+    let space = message.space()?;
+    if msg_acc.data_is_empty() {
+        // The payer funds the message account with the receiver's fee.
+        let lamports = rent.minimum_balance(space).saturating_add(fee);
+        if fee > 0 && payer_acc.lamports().saturating_add(msg_acc.lamports()) < lamports {
+            info!("Message fee not paid");
+            return Err(JabberError::InsufficientMessageFee.into());
+        }
+        create_derived_account(
+            payer_acc,
+            msg_acc,
+            system_program_acc,
+            &[
+                Message::SEED,
+                s_acc.key.as_ref(),
+                r_acc.key.as_ref(),
+                &thread.msg_count.to_le_bytes(),
+                &[msg_bump],
+            ],
+            lamports,
+            space,
+            program_id,
+        )?;
+    }
+    if msg_acc.owner != program_id {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    if msg_acc.data_len() < space {
+        return Err(JabberError::MessageTooLarge.into());
+    }
+
+    // first time?
+    if thread.msg_count == 1 {
+        // Update the thread tail for sender.
+        if s_profile_exists {
+            let mut s_data = s_profile_acc.try_borrow_mut_data()?;
+            let mut s = Profile::unpack(&s_data)?;
+            thread.prev_thread_u1_pk = s.thread_tail_pk;
+            s.thread_tail_pk = Some(thread_acc.key.to_bytes());
+            s.pack(&mut s_data)?;
+        }
+
+        // Update the thread tail for receiver. We add it to the program
+        // root account if their profile does not exist.
+        if r_profile_exists {
+            let mut r_data = r_profile_acc.try_borrow_mut_data()?;
+            let mut r = Profile::unpack(&r_data)?;
+            thread.prev_thread_u2_pk = r.thread_tail_pk;
+            r.thread_tail_pk = Some(thread_acc.key.to_bytes());
+            r.pack(&mut r_data)?;
+        } else {
+            // The reciever is not registered, point thread to unregistered users.
+            let mut jabber_data = jabber_acc.try_borrow_mut_data()?;
+            let mut jabber = Jabber::unpack(&jabber_data)?;
+            thread.prev_thread_u2_pk = jabber.unregistered_thread_tail_pk;
+            thread.unregistered = true;
+            jabber.unregistered_thread_tail_pk = Some(thread_acc.key.to_bytes());
+            jabber.pack(&mut jabber_data)?;
+        }
+    }
+
+    // Charge the fee set by the receiver, if any.
+    if fee > 0 {
+        let min_balance = rent.minimum_balance(msg_acc.data_len());
+        if msg_acc.lamports() < min_balance.saturating_add(fee) {
+            info!("Message fee not paid");
+            return Err(JabberError::InsufficientMessageFee.into());
+        }
+        **msg_acc.try_borrow_mut_lamports()? -= fee;
+        **r_acc.try_borrow_mut_lamports()? += fee;
+    }
+
+    let mut message_data = msg_acc.try_borrow_mut_data()?;
+    message.pack(&mut message_data)?;
+    thread.msg_count = thread.msg_count + 1;
+    thread.pack(&mut thread_data)?;
+    Ok(())
+}
+
+/// Checks the signer and owner of a group account, and decodes the group.
+fn unpack_group(
+    program_id: &Pubkey,
+    signer_acc: &AccountInfo,
+    group_acc: &AccountInfo,
+    group_data: &[u8],
+) -> Result<Group, ProgramError> {
+    if !signer_acc.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if group_acc.owner != program_id {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Group::unpack(
+        group_data
+            .get(..Group::SPACE)
+            .ok_or(ProgramError::AccountDataTooSmall)?,
+    )
+}
+
+/// Only the owner manages admins, admins manage posters.
+fn check_group_role_authority(
+    group: &Group,
+    signer_pk: &PublicKey,
+    role: GroupRole,
+) -> ProgramResult {
+    match role {
+        GroupRole::Admin if *signer_pk != group.owner => Err(JabberError::NotGroupOwner.into()),
+        GroupRole::Poster if !group.is_admin(signer_pk) => Err(JabberError::NotGroupAdmin.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Checks that `msg_acc` is message `index` sent by the signer `s_acc` to `r_acc`, and returns
+/// the current time.
+fn check_sent_message(
+    program_id: &Pubkey,
+    index: u32,
+    s_acc: &AccountInfo,
+    r_acc: &AccountInfo,
+    msg_acc: &AccountInfo,
+    sysvar_clock_acc: &AccountInfo,
+) -> Result<UnixTimestamp, ProgramError> {
+    if !s_acc.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if !clock::check_id(sysvar_clock_acc.key) {
+        info!("Clock system account is not valid");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    // Only the sender's key derives the message account.
+    if *msg_acc.key != Message::find_address(index, s_acc.key, r_acc.key, program_id).0 {
+        info!("Message account invalid");
+        return Err(JabberError::AccountNotDeterministic.into());
+    }
+    if msg_acc.owner != program_id {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(Clock::from_account_info(sysvar_clock_acc)?.unix_timestamp)
+}
+
+#[cfg(all(not(target_arch = "bpf"), not(feature = "no-entrypoint")))]
+solana_sdk::program_stubs!();
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::{CtaMessage, EncryptedMessage};
+    use solana_sdk::{account::Account, clock::Epoch, pubkey::Pubkey};
+
+    fn rand_pk() -> Pubkey {
+        Pubkey::new(&rand::random::<[u8; 32]>())
+    }
+
+    fn sys_pk() -> Pubkey {
+        let system_account_bytes: &[u8] = &[
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0,
+        ];
+        Pubkey::new(system_account_bytes)
+    }
+
+    fn create_account<'a>(
+        is_signer: bool,
+        is_writable: bool,
+        pk: &'a Pubkey,
+        owner: &'a Pubkey,
+        lamports: &'a mut u64,
+        data: &'a mut [u8],
+    ) -> AccountInfo<'a> {
+        AccountInfo::new(
+            &pk,
+            is_signer,
+            is_writable,
+            lamports,
+            data,
+            &owner,
+            false,
+            Epoch::default(),
+        )
+    }
+
+    #[test]
+    fn test_send_message() {
+        let pks = [rand_pk(), rand_pk(), rand_pk()];
+        let mut data = SendMessageData::new();
+        send_message("Hey!", 1, pks, &mut data).unwrap();
+
+        let msg = Message::unpack(&data.msg).unwrap();
+        let expected_msg = Message {
+            kind: 10,
+            msg: String::from("Hey!").into_bytes(),
+            timestamp: 0,
+            ttl: None,
+            edited: None,
+            prev_msg: None,
+            payer: None,
+            meta: vec![],
+        };
+        let jabber = Jabber::unpack(&data.jabber).unwrap();
+        let thread = Thread::unpack(&data.thread).unwrap();
+        let expected_thread = Thread {
+            msg_count: 2,
+            prev_thread_u1_pk: None,
+            prev_thread_u2_pk: None,
+            unregistered: true,
+            u1_pk: pks[1].to_bytes(),
+            u2_pk: pks[2].to_bytes(),
+            u1_ban: false,
+            u2_ban: false,
+            u1_state: ThreadState::default(),
+            u2_state: ThreadState::default(),
+        };
+        let thread_pk = Thread::find_address(&pks[1], &pks[2], &pks[0]).0;
+        assert_eq!(expected_msg, msg, "Test message");
+        assert_eq!(
+            jabber.unregistered_thread_tail_pk,
+            Some(thread_pk.to_bytes()),
+            "Test jabber tail thread"
+        );
+        assert_eq!(expected_thread, thread, "Test thread");
+
+        data.msg = vec![];
+        send_message("What's up?", 2, pks, &mut data).unwrap();
+
+        let msg = Message::unpack(&data.msg).unwrap();
+        let expected_msg = Message {
+            kind: 10,
+            msg: String::from("What's up?").into_bytes(),
+            timestamp: 0,
+            ttl: None,
+            edited: None,
+            prev_msg: None,
+            payer: None,
+            meta: vec![],
+        };
+        let jabber = Jabber::unpack(&data.jabber).unwrap();
+        let thread = Thread::unpack(&data.thread).unwrap();
+        let expected_thread = Thread {
+            msg_count: 3,
+            ..expected_thread
+        };
+
+        assert_eq!(expected_msg, msg);
+        assert_eq!(
+            jabber.unregistered_thread_tail_pk,
+            Some(thread_pk.to_bytes())
+        );
+        assert_eq!(expected_thread, thread);
+
+        // Send message to another account whose profile is registered
+        let pks = [pks[0], pks[1], rand_pk()];
+        let thread_pk = Thread::find_address(&pks[1], &pks[2], &pks[0]).0;
+        data.thread = vec![0; Thread::MIN_SPACE];
+        data.r_profile = profile_data(Profile::default());
+        data.msg = vec![];
+        send_message("bye", 1, pks, &mut data).unwrap();
+        let mut expected_r_profile = Profile::default();
+        expected_r_profile.thread_tail_pk = Some(thread_pk.to_bytes());
+        let r_profile = Profile::unpack(&data.r_profile).unwrap();
+        assert_eq!(expected_r_profile, r_profile);
+
+        // The receiver replies on the same thread
+        let pks = [pks[0], pks[2], pks[1]];
+        std::mem::swap(&mut data.s_profile, &mut data.r_profile);
+        data.msg = vec![];
+        send_message("Hello", 2, pks, &mut data).unwrap();
+        assert_eq!(Thread::unpack(&data.thread).unwrap().msg_count, 3);
+
+        // Existing message accounts have to fit the message
+        data.msg = vec![0; message_space(b"Hey!", None) - 1];
+        assert_eq!(
+            send_message("Hey!", 3, pks, &mut data),
+            Err(JabberError::MessageTooLarge.into())
+        );
+
+        // TODO: Check s_last_thread_data for all cases
+    }
+
+    #[test]
+    fn test_send_message_kind() {
+        let pks = [rand_pk(), rand_pk(), rand_pk()];
+        let mut data = SendMessageData::new();
+        let cta = |delivery| {
+            MessagePayload::Cta(CtaMessage {
+                title: String::from("Liquidation"),
+                body: String::from("Your loan is at risk"),
+                delivery,
+            })
+            .pack()
+            .unwrap()
+        };
+
+        data.kind = Message::CTA_KIND;
+        assert_eq!(
+            send_message(cta(-1), 1, pks, &mut data),
+            Err(JabberError::DeliveryInPast.into())
+        );
+        assert_eq!(
+            send_message("Hey!", 1, pks, &mut data),
+            Err(JabberError::InvalidMessagePayload.into())
+        );
+        data.kind = Message::RETRACTED_KIND;
+        assert_eq!(
+            send_message("", 1, pks, &mut data),
+            Err(JabberError::UnsupportedMessageKind.into())
+        );
+
+        data.kind = Message::CTA_KIND;
+        data.msg = vec![];
+        send_message(cta(0), 1, pks, &mut data).unwrap();
+        let msg = Message::unpack(&data.msg).unwrap();
+        assert_eq!(
+            MessagePayload::unpack(msg.kind, &msg.msg),
+            MessagePayload::unpack(Message::CTA_KIND, &cta(0))
+        );
+    }
+
+    #[test]
+    fn test_send_message_encrypted() {
+        let pks = [rand_pk(), rand_pk(), rand_pk()];
+        let mut data = SendMessageData::new();
+        data.kind = Message::ENCRYPTED_KIND;
+        data.r_profile = profile_data(Profile {
+            encryption_key: Some([1; 32]),
+            encryption_key_version: 2,
+            ..Profile::default()
+        });
+        let encrypted = |key_version| {
+            MessagePayload::Encrypted(EncryptedMessage {
+                key_version,
+                data: vec![1, 2, 3],
+            })
+            .pack()
+            .unwrap()
+        };
+
+        // Messages sealed to a rotated key are rejected.
+        assert_eq!(
+            send_message(encrypted(1), 1, pks, &mut data),
+            Err(JabberError::EncryptionKeyMismatch.into())
+        );
+        data.msg = vec![];
+        send_message(encrypted(2), 1, pks, &mut data).unwrap();
+    }
+
+    #[test]
+    fn test_send_message_reply() {
+        let pks = [rand_pk(), rand_pk(), rand_pk()];
+        let mut data = SendMessageData::new();
+        send_message("Hey!", 1, pks, &mut data).unwrap();
+        let parent_pk = Message::find_address(1, &pks[1], &pks[2], &pks[0]).0;
+        let parent_data = std::mem::take(&mut data.msg);
+
+        // Replies have to point to an earlier message in the thread.
+        for (index, pk) in [(1, rand_pk()), (2, parent_pk), (0, parent_pk)].iter() {
+            data.parent = Some((*index, *pk, parent_data.clone()));
+            assert_eq!(
+                send_message("Hey yourself", 2, pks, &mut data),
+                Err(JabberError::InvalidParentMessage.into())
+            );
+            data.msg = vec![];
+        }
+
+        // Closed messages can not be replied to.
+        data.parent = Some((1, parent_pk, vec![0; parent_data.len()]));
+        assert_eq!(
+            send_message("Hey yourself", 2, pks, &mut data),
+            Err(JabberError::InvalidParentMessage.into())
+        );
+        data.msg = vec![];
+
+        // The receiver replies to the first message.
+        let pks = [pks[0], pks[2], pks[1]];
+        data.s_profile = profile_data(Profile::default());
+        data.parent = Some((1, parent_pk, parent_data));
+        send_message("Hey yourself", 2, pks, &mut data).unwrap();
+        let msg = Message::unpack(&data.msg).unwrap();
+        assert_eq!(msg.prev_msg, Some(parent_pk.to_bytes()));
+    }
+
+    #[test]
+    fn test_send_message_ttl() {
+        let pks = [rand_pk(), rand_pk(), rand_pk()];
+        for ttl in [0, -60].iter() {
+            let mut data = SendMessageData::new();
+            data.ttl = Some(*ttl);
+            assert_eq!(
+                send_message("Hey!", 1, pks, &mut data),
+                Err(JabberError::InvalidTtl.into())
+            );
+        }
+
+        let mut data = SendMessageData::new();
+        data.ttl = Some(60);
+        data.msg = vec![0; message_space(b"Hey!", None) + 8];
+        send_message("Hey!", 1, pks, &mut data).unwrap();
+        assert_eq!(Message::unpack(&data.msg).unwrap().ttl, Some(60));
+    }
+
+    #[test]
+    fn test_notify() {
+        let program_id = rand_pk();
+        let sender_pk = rand_pk();
+        let payer_pk = rand_pk();
+        let r_pk = rand_pk();
+        let owner = sys_pk();
+        let instruction = notify(
+            &program_id,
+            &sender_pk,
+            &payer_pk,
+            &r_pk,
+            1,
+            10,
+            b"Liquidation".to_vec(),
+            None,
+            None,
+        )
+        .unwrap();
+
+        // Data, lamports and owner of the accounts in `instruction`, up to the sysvars. Neither
+        // the sender nor the receiver have a profile, the message has room for the payer.
+        let mut account_data = [
+            (vec![0], 0, owner),
+            (vec![0], 0, owner),
+            (vec![0], 0, owner),
+            (vec![0; Thread::MIN_SPACE], 10000000, program_id),
+            (vec![], 0, owner),
+            (vec![], 0, owner),
+            (
+                vec![0; message_space(b"Liquidation", None) + 32],
+                0,
+                program_id,
+            ),
+            (new_jabber_data(), 0, program_id),
+        ];
+        let mut rent_tuple = (rent::id(), test_rent().create_account(1));
+        let mut clock_tuple = (clock::id(), Clock::default().create_account(1));
+        let mut system_lamports = 0;
+        let mut system_data = vec![];
+        {
+            let mut accounts: Vec<_> = instruction
+                .accounts
+                .iter()
+                .zip(account_data.iter_mut())
+                .map(|(meta, (data, lamports, owner))| {
+                    create_account(
+                        meta.is_signer,
+                        meta.is_writable,
+                        &meta.pubkey,
+                        owner,
+                        lamports,
+                        data,
+                    )
+                })
+                .collect();
+            accounts.push(AccountInfo::from(&mut rent_tuple));
+            accounts.push(AccountInfo::from(&mut clock_tuple));
+            accounts.push(create_account(
+                false,
+                false,
+                &owner,
+                &owner,
+                &mut system_lamports,
+                &mut system_data,
+            ));
+            assert_eq!(accounts[accounts.len() - 1].key, &system_program::id());
+
+            accounts[0].is_signer = false;
+            assert_eq!(
+                JabberInstruction::process(&program_id, &accounts, &instruction.data),
+                Err(ProgramError::MissingRequiredSignature)
+            );
+            accounts[0].is_signer = true;
+            JabberInstruction::process(&program_id, &accounts, &instruction.data).unwrap();
+        }
+
+        let msg = Message::unpack(&account_data[6].0).unwrap();
+        assert_eq!(msg.msg, b"Liquidation".to_vec());
+        assert_eq!(msg.payer, Some(payer_pk.to_bytes()));
+        let thread = Thread::unpack(&account_data[3].0).unwrap();
+        assert_eq!(thread.u1_pk, sender_pk.to_bytes());
+        assert_eq!(thread.msg_count, 2);
+        assert_eq!(thread.prev_thread_u1_pk, None);
+        let jabber = Jabber::unpack(&account_data[7].0).unwrap();
+        assert_eq!(
+            jabber.unregistered_thread_tail_pk,
+            Some(instruction.accounts[3].pubkey.to_bytes())
+        );
+    }
+
+    #[test]
+    fn test_instruction_builders() {
+        let program_id = rand_pk();
+        let s_pk = rand_pk();
+        let r_pk = rand_pk();
+
+        let instruction =
+            super::set_user_profile(&program_id, &s_pk, Some("Alpaca".into()), None, None, None)
+                .unwrap();
+        let keys: Vec<_> = instruction.accounts.iter().map(|m| m.pubkey).collect();
+        assert_eq!(
+            keys,
+            vec![
+                s_pk,
+                Profile::find_address(&s_pk, &program_id).0,
+                rent::id(),
+                system_program::id()
+            ]
+        );
+        assert!(instruction.accounts[0].is_signer);
+
+        let instruction = super::send_message(
+            &program_id,
+            &s_pk,
+            &r_pk,
+            3,
+            10,
+            b"Hey".to_vec(),
+            None,
+            Some((2, &r_pk)),
+        )
+        .unwrap();
+        assert_eq!(instruction.accounts.len(), 11);
+        assert!(instruction.accounts[0].is_signer && instruction.accounts[0].is_writable);
+        assert_eq!(
+            instruction.accounts[2].pubkey,
+            Thread::find_address(&r_pk, &s_pk, &program_id).0
+        );
+        assert_eq!(
+            instruction.accounts[5].pubkey,
+            Message::find_address(3, &s_pk, &r_pk, &program_id).0
+        );
+        assert_eq!(
+            instruction.accounts[10].pubkey,
+            Message::find_address(2, &r_pk, &s_pk, &program_id).0
+        );
+        match JabberInstruction::try_from_slice(&instruction.data).unwrap() {
+            JabberInstruction::SendMessage { prev_msg, .. } => assert_eq!(prev_msg, Some(2)),
+            _ => panic!("Unexpected instruction"),
+        }
+    }
+
+    #[test]
+    fn test_send_message_fee() {
+        let pks = [rand_pk(), rand_pk(), rand_pk()];
+        let min_balance = test_rent().minimum_balance(message_space(b"Hey!", None));
+        let r_profile = profile_data(Profile {
+            lamports_per_message: 50,
+            ..Profile::default()
+        });
+
+        // Message account does not hold the fee.
+        let mut data = SendMessageData::new();
+        data.r_profile = r_profile.clone();
+        data.msg_lamports = min_balance + 49;
+        assert_eq!(
+            send_message("Hey!", 1, pks, &mut data),
+            Err(JabberError::InsufficientMessageFee.into()),
+            "Test unpaid fee"
+        );
+        assert_eq!(data.r_lamports, 0);
+        assert_eq!(data.msg_lamports, min_balance + 49);
+
+        // Fee is moved to the receiver.
+        let mut data = SendMessageData::new();
+        data.r_profile = r_profile.clone();
+        data.msg_lamports = min_balance + 50;
+        send_message("Hey!", 1, pks, &mut data).unwrap();
+        assert_eq!(data.r_lamports, 50, "Test paid fee");
+        assert_eq!(data.msg_lamports, min_balance);
+
+        // No fee when the receiver does not charge for messages.
+        let mut data = SendMessageData::new();
+        data.r_profile = profile_data(Profile::default());
+        send_message("Hey!", 1, pks, &mut data).unwrap();
+        assert_eq!(data.r_lamports, 0, "Test free messages");
+
+        // The sender can not fund a new message account with the fee.
+        let mut data = SendMessageData::new();
+        data.r_profile = r_profile;
+        data.create_msg = true;
+        data.s_lamports = min_balance + 48;
+        data.msg_lamports = 1;
+        assert_eq!(
+            send_message("Hey!", 1, pks, &mut data),
+            Err(JabberError::InsufficientMessageFee.into()),
+            "Test unpaid fee on a new message account"
+        );
+        assert_eq!((data.s_lamports, data.r_lamports), (min_balance + 48, 0));
+    }
+
+    #[test]
+    fn test_create_funded_account() {
+        let [program_id, s_pk, r_pk] = [rand_pk(), rand_pk(), rand_pk()];
+        // Anyone can fund the address of the next message in a thread.
+        let msg_pk = Message::find_address(3, &s_pk, &r_pk, &program_id).0;
+        let space = message_space(b"Hey!", None);
+        let lamports = test_rent().minimum_balance(space);
+
+        assert_eq!(
+            derived_account_instructions(&s_pk, &msg_pk, 0, lamports, space, &program_id),
+            vec![system_instruction::create_account(
+                &s_pk,
+                &msg_pk,
+                lamports,
+                space as u64,
+                &program_id
+            )]
+        );
+        assert_eq!(
+            derived_account_instructions(&s_pk, &msg_pk, 1, lamports, space, &program_id),
+            vec![
+                system_instruction::transfer(&s_pk, &msg_pk, lamports - 1),
+                system_instruction::allocate(&msg_pk, space as u64),
+                system_instruction::assign(&msg_pk, &program_id),
+            ]
+        );
+        assert_eq!(
+            derived_account_instructions(&s_pk, &msg_pk, lamports, lamports, space, &program_id),
+            vec![
+                system_instruction::allocate(&msg_pk, space as u64),
+                system_instruction::assign(&msg_pk, &program_id),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_thread_ban() {
+        let pks = [rand_pk(), rand_pk(), rand_pk()];
+        let program_id = pks[0];
+        let thread_pk = Thread::find_address(&pks[1], &pks[2], &program_id).0;
+        let mut data = SendMessageData::new();
+        Thread {
+            msg_count: 2,
+            prev_thread_u1_pk: None,
+            prev_thread_u2_pk: None,
+            unregistered: false,
+            u1_pk: pks[1].to_bytes(),
+            u2_pk: pks[2].to_bytes(),
+            u1_ban: false,
+            u2_ban: false,
+            u1_state: ThreadState::default(),
+            u2_state: ThreadState::default(),
+        }
+        .pack(&mut data.thread)
+        .unwrap();
+
+        let set_ban = |user_pk: &Pubkey, ban: bool, thread_data: &mut Vec<u8>| {
+            let owner = sys_pk();
+            let mut user_lamports = 0;
+            let mut user_data = vec![0];
+            let mut thread_lamports = 0;
+            let accounts = [
+                create_account(
+                    true,
+                    false,
+                    user_pk,
+                    &owner,
+                    &mut user_lamports,
+                    &mut user_data,
+                ),
+                create_account(
+                    false,
+                    true,
+                    &thread_pk,
+                    &program_id,
+                    &mut thread_lamports,
+                    thread_data,
+                ),
+            ];
+            let instruction = JabberInstruction::SetThreadBan { ban };
+            JabberInstruction::process(&program_id, &accounts, &instruction.try_to_vec().unwrap())
+        };
+
+        // Only participants can ban a thread.
+        assert_eq!(
+            set_ban(&rand_pk(), true, &mut data.thread),
+            Err(JabberError::AccountNotAuthorized.into())
+        );
+
+        set_ban(&pks[2], true, &mut data.thread).unwrap();
+        let thread = Thread::unpack(&data.thread).unwrap();
+        assert_eq!((thread.u1_ban, thread.u2_ban), (false, true));
+
+        // u1 can no longer message u2.
+        assert_eq!(
+            send_message("Hey!", 2, pks, &mut data),
+            Err(JabberError::ThreadBanned.into())
+        );
+
+        set_ban(&pks[2], false, &mut data.thread).unwrap();
+        send_message("Hey!", 2, pks, &mut data).unwrap();
+        assert_eq!(Thread::unpack(&data.thread).unwrap().msg_count, 3);
+    }
+
+    #[test]
+    fn test_set_thread_state() {
+        let program_id = rand_pk();
+        let u1_pk = rand_pk();
+        let u2_pk = rand_pk();
+        let thread_pk = Thread::find_address(&u1_pk, &u2_pk, &program_id).0;
+        let mut thread_data = vec![0; Thread::MIN_SPACE];
+        Thread {
+            msg_count: 3,
+            prev_thread_u1_pk: None,
+            prev_thread_u2_pk: None,
+            unregistered: false,
+            u1_pk: u1_pk.to_bytes(),
+            u2_pk: u2_pk.to_bytes(),
+            u1_ban: false,
+            u2_ban: false,
+            u1_state: ThreadState::default(),
+            u2_state: ThreadState::default(),
+        }
+        .pack(&mut thread_data)
+        .unwrap();
+
+        let set_state = |user_pk: &Pubkey,
+                         instruction: JabberInstruction,
+                         thread_data: &mut [u8]| {
+            let owner = sys_pk();
+            let mut user_lamports = 0;
+            let mut user_data = vec![0];
+            let mut thread_lamports = 0;
+            let accounts = [
+                create_account(
+                    true,
+                    false,
+                    user_pk,
+                    &owner,
+                    &mut user_lamports,
+                    &mut user_data,
+                ),
+                create_account(
+                    false,
+                    true,
+                    &thread_pk,
+                    &program_id,
+                    &mut thread_lamports,
+                    thread_data,
+                ),
+            ];
+            JabberInstruction::process(&program_id, &accounts, &instruction.try_to_vec().unwrap())
+        };
+
+        let read = |last_read| JabberInstruction::SetThreadState {
+            last_read: Some(last_read),
+            muted: None,
+            archived: None,
+        };
+        assert_eq!(
+            set_state(&rand_pk(), read(1), &mut thread_data),
+            Err(JabberError::AccountNotAuthorized.into())
+        );
+        assert_eq!(
+            set_state(&u2_pk, read(3), &mut thread_data),
+            Err(JabberError::InvalidMessageIndex.into())
+        );
+        set_state(&u2_pk, read(2), &mut thread_data).unwrap();
+        set_state(
+            &u2_pk,
+            JabberInstruction::SetThreadState {
+                last_read: None,
+                muted: Some(true),
+                archived: None,
+            },
+            &mut thread_data,
+        )
+        .unwrap();
+        set_state(
+            &u1_pk,
+            JabberInstruction::SetThreadState {
+                last_read: Some(1),
+                muted: None,
+                archived: Some(true),
+            },
+            &mut thread_data,
+        )
+        .unwrap();
+
+        let thread = Thread::unpack(&thread_data).unwrap();
+        assert_eq!(
+            thread.u1_state,
+            ThreadState {
+                last_read: 1,
+                muted: false,
+                archived: true,
+            }
+        );
+        assert_eq!(
+            thread.u2_state,
+            ThreadState {
+                last_read: 2,
+                muted: true,
+                archived: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_close_message() {
+        let program_id = rand_pk();
+        let s_pk = rand_pk();
+        let r_pk = rand_pk();
+        let payer_pk = rand_pk();
+        let msg_pk = Message::find_address(1, &s_pk, &r_pk, &program_id).0;
+        let message = |ttl, payer: Option<&Pubkey>| {
+            let mut msg_data = vec![0; 100];
+            Message {
+                kind: 10,
+                msg: String::from("Hey!").into_bytes(),
+                timestamp: 100,
+                ttl,
+                edited: None,
+                prev_msg: None,
+                payer: payer.map(|pk| pk.to_bytes()),
+                meta: vec![],
+            }
+            .pack(&mut msg_data)
+            .unwrap();
+            msg_data
+        };
+
+        // Returns the lamports of the message, the sender and the payer after closing.
+        let close = |closer_pk: &Pubkey, now: UnixTimestamp, msg_data: &mut Vec<u8>| {
+            let owner = sys_pk();
+            let mut closer_lamports = 0;
+            let mut closer_data = vec![0];
+            let closer_acc = create_account(
+                true,
+                false,
+                closer_pk,
+                &owner,
+                &mut closer_lamports,
+                &mut closer_data,
+            );
+            let mut s_lamports = 0;
+            let mut s_data = vec![0];
+            let s_acc = create_account(false, true, &s_pk, &owner, &mut s_lamports, &mut s_data);
+            let mut r_lamports = 0;
+            let mut r_data = vec![0];
+            let r_acc = create_account(false, false, &r_pk, &owner, &mut r_lamports, &mut r_data);
+            let mut msg_lamports = 500;
+            let msg_acc = create_account(
+                false,
+                true,
+                &msg_pk,
+                &program_id,
+                &mut msg_lamports,
+                msg_data,
+            );
+
+            let clock = Clock {
+                unix_timestamp: now,
+                ..Clock::default()
+            };
+            let mut clock_tuple = (clock::id(), clock.create_account(1));
+            let clock_info = AccountInfo::from(&mut clock_tuple);
+            let mut payer_lamports = 0;
+            let mut payer_data = vec![0];
+            let payer_acc = create_account(
+                false,
+                true,
+                &payer_pk,
+                &owner,
+                &mut payer_lamports,
+                &mut payer_data,
+            );
+
+            let accounts = [closer_acc, s_acc, r_acc, msg_acc, clock_info, payer_acc];
+            let instruction = JabberInstruction::CloseMessage { index: 1 };
+            JabberInstruction::process(&program_id, &accounts, &instruction.try_to_vec().unwrap())?;
+            drop(accounts);
+            Ok((msg_lamports, s_lamports, payer_lamports))
+        };
+
+        // Others have to wait for the message to expire.
+        let mut msg_data = message(Some(60), None);
+        assert_eq!(
+            close(&rand_pk(), 159, &mut msg_data),
+            Err(JabberError::MessageNotExpired.into())
+        );
+        assert_eq!(close(&rand_pk(), 160, &mut msg_data), Ok((0, 500, 0)));
+        assert_eq!(msg_data, vec![0; 100]);
+
+        // Sender can close at any time.
+        let mut msg_data = message(None, None);
+        assert_eq!(
+            close(&rand_pk(), 1000, &mut msg_data),
+            Err(JabberError::MessageNotExpired.into())
+        );
+        assert_eq!(close(&s_pk, 0, &mut msg_data), Ok((0, 500, 0)));
+        assert_eq!(msg_data, vec![0; 100]);
+
+        // The payer of a notification gets the refund.
+        let mut msg_data = message(None, Some(&rand_pk()));
+        assert_eq!(
+            close(&s_pk, 0, &mut msg_data),
+            Err(ProgramError::InvalidArgument)
+        );
+        let mut msg_data = message(None, Some(&payer_pk));
+        assert_eq!(close(&s_pk, 0, &mut msg_data), Ok((0, 0, 500)));
+        assert_eq!(msg_data, vec![0; 100]);
+    }
+
+    #[test]
+    fn test_annotate_message() {
+        let pks = [rand_pk(), rand_pk(), rand_pk()];
+        let [program_id, s_pk, r_pk] = pks;
+        let mut data = SendMessageData::new();
+        send_message("Hey!", 1, pks, &mut data).unwrap();
+        let msg_pk = Message::find_address(1, &s_pk, &r_pk, &program_id).0;
+
+        let annotate = |user_pk: &Pubkey, kind: MetaKind, msg_data: &mut Vec<u8>| {
+            let owner = sys_pk();
+            let mut lamports = 0;
+            let mut user_data = vec![0];
+            let user_acc =
+                create_account(true, false, user_pk, &owner, &mut lamports, &mut user_data);
+            let mut lamports = 0;
+            let mut s_data = vec![0];
+            let s_acc = create_account(false, false, &s_pk, &owner, &mut lamports, &mut s_data);
+            let mut lamports = 0;
+            let mut r_data = vec![0];
+            let r_acc = create_account(false, false, &r_pk, &owner, &mut lamports, &mut r_data);
+            let mut lamports = 0;
+            let msg_acc =
+                create_account(false, true, &msg_pk, &program_id, &mut lamports, msg_data);
+
+            let clock = Clock {
+                unix_timestamp: 100,
+                ..Clock::default()
+            };
+            let mut clock_tuple = (clock::id(), clock.create_account(1));
+            let clock_info = AccountInfo::from(&mut clock_tuple);
+
+            let accounts = [user_acc, s_acc, r_acc, msg_acc, clock_info];
+            let instruction = JabberInstruction::AnnotateMessage { index: 1, kind };
+            JabberInstruction::process(&program_id, &accounts, &instruction.try_to_vec().unwrap())
+        };
+
+        assert_eq!(
+            annotate(&rand_pk(), MetaKind::Read, &mut data.msg),
+            Err(JabberError::AccountNotAuthorized.into())
+        );
+        annotate(&r_pk, MetaKind::Read, &mut data.msg).unwrap();
+        annotate(&r_pk, MetaKind::Read, &mut data.msg).unwrap();
+        annotate(&s_pk, MetaKind::Reaction(0x1F44D), &mut data.msg).unwrap();
+        let meta = Message::unpack(&data.msg).unwrap().meta;
+        assert_eq!(
+            meta,
+            vec![
+                MessageMeta {
+                    by_sender: false,
+                    kind: MetaKind::Read,
+                    timestamp: 100,
+                },
+                MessageMeta {
+                    by_sender: true,
+                    kind: MetaKind::Reaction(0x1F44D),
+                    timestamp: 100,
+                },
+            ]
+        );
+
+        // Both users fill up their entries in the reserved space.
+        for i in 1..Message::MAX_META_PER_USER as u32 {
+            annotate(&r_pk, MetaKind::Reaction(i), &mut data.msg).unwrap();
+            annotate(&s_pk, MetaKind::Reaction(i), &mut data.msg).unwrap();
+        }
+        assert_eq!(
+            annotate(&r_pk, MetaKind::Reaction(0x1F44D), &mut data.msg),
+            Err(JabberError::TooManyMessageMeta.into())
+        );
+        assert_eq!(
+            Message::unpack(&data.msg).unwrap().meta.len(),
+            2 * Message::MAX_META_PER_USER
+        );
+    }
+
+    #[test]
+    fn test_edit_message() {
+        let pks = [rand_pk(), rand_pk(), rand_pk()];
+        let [program_id, s_pk, r_pk] = pks;
+        let mut data = SendMessageData::new();
+        send_message("Hey!", 1, pks, &mut data).unwrap();
+        let msg_pk = Message::find_address(1, &s_pk, &r_pk, &program_id).0;
+
+        let edit = |signer_pk: &Pubkey, instruction: JabberInstruction, msg_data: &mut Vec<u8>| {
+            let owner = sys_pk();
+            let (s_pk, r_pk) = if *signer_pk == s_pk {
+                (s_pk, r_pk)
+            } else {
+                (r_pk, s_pk)
+            };
+            let mut lamports = 0;
+            let mut s_data = vec![0];
+            let s_acc = create_account(true, false, &s_pk, &owner, &mut lamports, &mut s_data);
+            let mut lamports = 0;
+            let mut r_data = vec![0];
+            let r_acc = create_account(false, false, &r_pk, &owner, &mut lamports, &mut r_data);
+            let mut lamports = 0;
+            let msg_acc =
+                create_account(false, true, &msg_pk, &program_id, &mut lamports, msg_data);
+
+            let clock = Clock {
+                unix_timestamp: 100,
+                ..Clock::default()
+            };
+            let mut clock_tuple = (clock::id(), clock.create_account(1));
+            let clock_info = AccountInfo::from(&mut clock_tuple);
+
+            let accounts = [s_acc, r_acc, msg_acc, clock_info];
+            JabberInstruction::process(&program_id, &accounts, &instruction.try_to_vec().unwrap())
+        };
+        let edit_msg = |msg: &str| JabberInstruction::EditMessage {
+            index: 1,
+            msg: msg.as_bytes().to_vec(),
+        };
+
+        // Only the sender can change the message.
+        assert_eq!(
+            edit(&r_pk, edit_msg("Bye!"), &mut data.msg),
+            Err(JabberError::AccountNotDeterministic.into())
+        );
+        assert_eq!(
+            edit(
+                &r_pk,
+                JabberInstruction::RetractMessage { index: 1 },
+                &mut data.msg
+            ),
+            Err(JabberError::AccountNotDeterministic.into())
+        );
+        assert_eq!(
+            edit(&s_pk, edit_msg("Hey there!"), &mut data.msg),
+            Err(JabberError::MessageTooLarge.into())
+        );
+
+        edit(&s_pk, edit_msg("Bye!"), &mut data.msg).unwrap();
+        let msg = Message::unpack(&data.msg).unwrap();
+        assert_eq!(msg.msg, b"Bye!".to_vec());
+        assert_eq!(msg.edited, Some(100));
+
+        // Encrypted messages can't be edited.
+        let mut encrypted_data = data.msg.clone();
+        let mut encrypted = msg;
+        encrypted.kind = Message::ENCRYPTED_KIND;
+        encrypted.pack(&mut encrypted_data).unwrap();
+        assert_eq!(
+            edit(&s_pk, edit_msg("Hey!"), &mut encrypted_data),
+            Err(JabberError::UnsupportedMessageKind.into())
+        );
+
+        edit(
+            &s_pk,
+            JabberInstruction::RetractMessage { index: 1 },
+            &mut data.msg,
+        )
+        .unwrap();
+        let msg = Message::unpack(&data.msg).unwrap();
+        assert_eq!(msg.kind, Message::RETRACTED_KIND);
+        assert!(msg.msg.is_empty());
+        assert_eq!(
+            edit(&s_pk, edit_msg("Hey!"), &mut data.msg),
+            Err(JabberError::MessageRetracted.into())
+        );
+    }
+
+    #[test]
+    fn test_initialize_jabber() {
+        let program_id = rand_pk();
+        let jabber_pk = Jabber::get_account(&program_id).unwrap();
+        let rent = test_rent();
+        let mut jabber_data = vec![0; Jabber::SPACE];
+
+        let initialize = |owner_pk: &Pubkey, jabber_data: &mut Vec<u8>| {
+            let owner = sys_pk();
+            let mut owner_lamports = 0;
+            let mut owner_data = vec![0];
+            let owner_acc = create_account(
+                true,
+                true,
+                owner_pk,
+                &owner,
+                &mut owner_lamports,
+                &mut owner_data,
+            );
+            let mut jabber_lamports = rent.minimum_balance(Jabber::SPACE);
+            let jabber_acc = create_account(
+                false,
+                true,
+                &jabber_pk,
+                &program_id,
+                &mut jabber_lamports,
+                jabber_data,
+            );
+            let mut rent_tuple = (rent::id(), rent.create_account(1));
+            let rent_info = AccountInfo::from(&mut rent_tuple);
+            let mut system_lamports = 0;
+            let mut system_data = vec![];
+            let system_acc = create_account(
+                false,
+                false,
+                &owner,
+                &owner,
+                &mut system_lamports,
+                &mut system_data,
+            );
+
+            let accounts = [owner_acc, jabber_acc, rent_info, system_acc];
+            let instruction = JabberInstruction::InitializeJabber;
+            JabberInstruction::process(&program_id, &accounts, &instruction.try_to_vec().unwrap())
+        };
+
+        assert_eq!(
+            initialize(&rand_pk(), &mut jabber_data),
+            Err(JabberError::AccountNotAuthorized.into())
+        );
+
+        initialize(&owner_account::id(), &mut jabber_data).unwrap();
+        let expected_jabber = Jabber {
+            unregistered_thread_tail_pk: None,
+        };
+        assert_eq!(Jabber::unpack(&jabber_data).unwrap(), expected_jabber);
+
+        assert_eq!(
+            initialize(&owner_account::id(), &mut jabber_data),
+            Err(JabberError::AccountAlreadyInitialized.into())
+        );
+    }
+
+    /// Processes a group instruction signed by `signer_pk`.
+    fn group_instruction(
+        program_id: &Pubkey,
+        group_pk: &Pubkey,
+        signer_pk: &Pubkey,
+        instruction: JabberInstruction,
+        group_data: &mut [u8],
+    ) -> ProgramResult {
+        let owner = sys_pk();
+        let mut lamports = 0;
+        let mut signer_data = vec![0];
+        let signer_acc = create_account(
+            true,
+            false,
+            signer_pk,
+            &owner,
+            &mut lamports,
+            &mut signer_data,
+        );
+        // The group account only signs when it is created.
+        let group_signs = matches!(instruction, JabberInstruction::CreateGroup { .. });
+        let mut group_lamports = test_rent().minimum_balance(group_data.len());
+        let group_acc = create_account(
+            group_signs,
+            true,
+            group_pk,
+            program_id,
+            &mut group_lamports,
+            group_data,
+        );
+        let mut sysvar_tuple = match instruction {
+            JabberInstruction::CreateGroup { .. } => (rent::id(), test_rent().create_account(1)),
+            _ => (
+                clock::id(),
+                Clock {
+                    unix_timestamp: 100,
+                    ..Clock::default()
+                }
+                .create_account(1),
+            ),
+        };
+        let sysvar_info = AccountInfo::from(&mut sysvar_tuple);
+        let accounts = [signer_acc, group_acc, sysvar_info];
+        JabberInstruction::process(program_id, &accounts, &instruction.try_to_vec().unwrap())
+    }
+
+    #[test]
+    fn test_group_accounts() {
+        let program_id = rand_pk();
+        let creator_pk = rand_pk();
+        let group_pk = rand_pk();
+        let owner = sys_pk();
+        let max_msg_len = 8;
+
+        // The group account has to sign its creation.
+        let mut lamports = 0;
+        let mut creator_data = vec![0];
+        let mut group_data = vec![0; Group::SPACE + Group::slot_space(max_msg_len)];
+        let mut group_lamports = test_rent().minimum_balance(group_data.len());
+        let mut rent_tuple = (rent::id(), test_rent().create_account(1));
+        let accounts = [
+            create_account(
+                true,
+                false,
+                &creator_pk,
+                &owner,
+                &mut lamports,
+                &mut creator_data,
+            ),
+            create_account(
+                false,
+                true,
+                &group_pk,
+                &program_id,
+                &mut group_lamports,
+                &mut group_data,
+            ),
+            AccountInfo::from(&mut rent_tuple),
+        ];
+        let create = JabberInstruction::CreateGroup {
+            max_msg_len,
+            post_policy: PostPolicy::Members,
+            admins: vec![],
+            members: vec![],
+        };
+        assert_eq!(
+            JabberInstruction::process(&program_id, &accounts, &create.try_to_vec().unwrap()),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+
+        // Accounts too small for a group are rejected.
+        let mut thread_data = vec![0; Thread::MIN_SPACE];
+        let instructions = vec![
+            JabberInstruction::AddGroupMember {
+                member: rand_pk().to_bytes(),
+            },
+            JabberInstruction::PostGroupMessage {
+                kind: 10,
+                msg: b"Hey!".to_vec(),
+            },
+        ];
+        for instruction in instructions {
+            assert_eq!(
+                group_instruction(
+                    &program_id,
+                    &group_pk,
+                    &creator_pk,
+                    instruction,
+                    &mut thread_data
+                ),
+                Err(ProgramError::AccountDataTooSmall)
+            );
+        }
+    }
+
+    #[test]
+    fn test_group() {
+        let program_id = rand_pk();
+        let creator_pk = rand_pk();
+        let member_pk = rand_pk();
+        let group_pk = rand_pk();
+        let max_msg_len = 8;
+        let mut group_data = vec![0; Group::SPACE + 2 * Group::slot_space(max_msg_len) + 1];
+        let process = |signer_pk: &Pubkey, instruction, group_data: &mut Vec<u8>| {
+            group_instruction(&program_id, &group_pk, signer_pk, instruction, group_data)
+        };
+        let post = |msg: &str| JabberInstruction::PostGroupMessage {
+            kind: 10,
+            msg: msg.as_bytes().to_vec(),
+        };
+
+        process(
+            &creator_pk,
+            JabberInstruction::CreateGroup {
+                max_msg_len,
+                post_policy: PostPolicy::Members,
+                admins: vec![],
+                members: vec![],
+            },
+            &mut group_data,
+        )
+        .unwrap();
+        let group = Group::unpack(&group_data[..Group::SPACE]).unwrap();
+        assert_eq!(group.capacity, 2);
+        assert_eq!(group.owner, creator_pk.to_bytes());
+        assert!(group.is_member(&creator_pk.to_bytes()));
+
+        // Only members can post, only admins can add members.
+        assert_eq!(
+            process(&member_pk, post("Hey!"), &mut group_data),
+            Err(JabberError::NotGroupMember.into())
+        );
+        let add = JabberInstruction::AddGroupMember {
+            member: member_pk.to_bytes(),
+        };
+        assert_eq!(
+            process(&member_pk, add, &mut group_data),
+            Err(JabberError::NotGroupAdmin.into())
+        );
+        let add = JabberInstruction::AddGroupMember {
+            member: member_pk.to_bytes(),
+        };
+        process(&creator_pk, add, &mut group_data).unwrap();
+        assert_eq!(
+            process(&member_pk, post("Hey there!"), &mut group_data),
+            Err(JabberError::MessageTooLarge.into())
+        );
+
+        // The third message overwrites the first one.
+        for msg in ["one", "two", "three"].iter() {
+            process(&member_pk, post(msg), &mut group_data).unwrap();
+        }
+        let group = Group::unpack(&group_data[..Group::SPACE]).unwrap();
+        assert_eq!(group.msg_count, 3);
+        assert_eq!(group.read_message(&group_data, 0), Ok(None));
+        assert_eq!(
+            group.read_message(&group_data, 1).unwrap().unwrap().msg,
+            b"two".to_vec()
+        );
+        assert_eq!(
+            group.read_message(&group_data, 2).unwrap(),
+            Some(GroupMessage {
+                sender: member_pk.to_bytes(),
+                kind: 10,
+                timestamp: 100,
+                msg: b"three".to_vec(),
+            })
+        );
+        assert_eq!(group.read_message(&group_data, 3), Ok(None));
 
-                Ok(())
-            }
-            _ => Err(ProgramError::InvalidInstructionData),
-        }
+        // Members can leave on their own.
+        let remove = JabberInstruction::RemoveGroupMember {
+            member: member_pk.to_bytes(),
+        };
+        process(&member_pk, remove, &mut group_data).unwrap();
+        assert_eq!(
+            process(&member_pk, post("Hey!"), &mut group_data),
+            Err(JabberError::NotGroupMember.into())
+        );
     }
-}
 
-#[cfg(not(target_arch = "bpf"))]
-solana_sdk::program_stubs!();
+    #[test]
+    fn test_group_roles() {
+        let program_id = rand_pk();
+        let group_pk = rand_pk();
+        let [owner_pk, admin_pk, poster_pk, member_pk] =
+            [rand_pk(), rand_pk(), rand_pk(), rand_pk()];
+        let mut group_data = vec![0; Group::SPACE + Group::slot_space(8)];
+        let mut process = |signer_pk: &Pubkey, instruction| {
+            group_instruction(
+                &program_id,
+                &group_pk,
+                signer_pk,
+                instruction,
+                &mut group_data,
+            )
+        };
+        let post = || JabberInstruction::PostGroupMessage {
+            kind: 10,
+            msg: b"Hey!".to_vec(),
+        };
+        let grant = |user: &Pubkey, role| JabberInstruction::GrantGroupRole {
+            user: user.to_bytes(),
+            role,
+        };
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use solana_sdk::{clock::Epoch, pubkey::Pubkey};
+        process(
+            &owner_pk,
+            JabberInstruction::CreateGroup {
+                max_msg_len: 8,
+                post_policy: PostPolicy::Posters,
+                admins: vec![],
+                members: vec![member_pk.to_bytes(), poster_pk.to_bytes()],
+            },
+        )
+        .unwrap();
 
-    fn rand_pk() -> Pubkey {
-        Pubkey::new(&rand::random::<[u8; 32]>())
-    }
+        // Only the owner manages admins, admins manage posters.
+        assert_eq!(
+            process(&member_pk, grant(&admin_pk, GroupRole::Admin)),
+            Err(JabberError::NotGroupOwner.into())
+        );
+        process(&owner_pk, grant(&admin_pk, GroupRole::Admin)).unwrap();
+        assert_eq!(
+            process(&member_pk, grant(&poster_pk, GroupRole::Poster)),
+            Err(JabberError::NotGroupAdmin.into())
+        );
+        process(&admin_pk, grant(&poster_pk, GroupRole::Poster)).unwrap();
 
-    fn sys_pk() -> Pubkey {
-        let system_account_bytes: &[u8] = &[
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0,
-        ];
-        Pubkey::new(system_account_bytes)
+        // Members can only read an announcement group.
+        assert_eq!(
+            process(&member_pk, post()),
+            Err(JabberError::GroupPostingRestricted.into())
+        );
+        assert_eq!(
+            process(&rand_pk(), post()),
+            Err(JabberError::NotGroupMember.into())
+        );
+        process(&poster_pk, post()).unwrap();
+        process(&admin_pk, post()).unwrap();
+
+        process(
+            &admin_pk,
+            JabberInstruction::SetGroupPostPolicy {
+                post_policy: PostPolicy::Admins,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            process(&poster_pk, post()),
+            Err(JabberError::GroupPostingRestricted.into())
+        );
+        process(
+            &admin_pk,
+            JabberInstruction::RevokeGroupRole {
+                user: poster_pk.to_bytes(),
+                role: GroupRole::Poster,
+            },
+        )
+        .unwrap();
+
+        // The previous owner stays on as an admin.
+        let transfer = || JabberInstruction::TransferGroupOwnership {
+            new_owner: admin_pk.to_bytes(),
+        };
+        assert_eq!(
+            process(&admin_pk, transfer()),
+            Err(JabberError::NotGroupOwner.into())
+        );
+        process(&owner_pk, transfer()).unwrap();
+        let remove_owner = JabberInstruction::RemoveGroupMember {
+            member: owner_pk.to_bytes(),
+        };
+        process(&admin_pk, remove_owner).unwrap();
+
+        let group = Group::unpack(&group_data[..Group::SPACE]).unwrap();
+        assert_eq!(group.owner, admin_pk.to_bytes());
+        assert!(group.admins.is_empty());
+        assert!(group.posters.is_empty());
+        assert_eq!(
+            group.members,
+            vec![member_pk.to_bytes(), poster_pk.to_bytes()]
+        );
+        assert_eq!(group.msg_count, 2);
     }
 
-    fn create_account<'a>(
-        is_signer: bool,
-        is_writable: bool,
-        pk: &'a Pubkey,
-        owner: &'a Pubkey,
-        lamports: &'a mut u64,
-        data: &'a mut [u8],
-    ) -> AccountInfo<'a> {
-        AccountInfo::new(
-            &pk,
-            is_signer,
-            is_writable,
-            lamports,
-            data,
+    /// Processes `instruction` with accounts in order: the signer, the program owned `accounts`
+    /// and the `sysvars`, which can include the system program.
+    fn process_with(
+        program_id: &Pubkey,
+        signer: (&Pubkey, &mut u64),
+        accounts: &mut [(Pubkey, Vec<u8>, u64)],
+        sysvars: &[Pubkey],
+        instruction: JabberInstruction,
+    ) -> ProgramResult {
+        let owner = sys_pk();
+        let mut signer_data = vec![0];
+        let mut infos = vec![create_account(
+            true,
+            true,
+            signer.0,
             &owner,
-            false,
-            Epoch::default(),
-        )
+            signer.1,
+            &mut signer_data,
+        )];
+        for (pk, data, lamports) in accounts.iter_mut() {
+            infos.push(create_account(false, true, pk, program_id, lamports, data));
+        }
+        let mut sysvar_accounts: Vec<_> = sysvars
+            .iter()
+            .map(|id| {
+                let account = if rent::check_id(id) {
+                    test_rent().create_account(1)
+                } else if clock::check_id(id) {
+                    Clock::default().create_account(1)
+                } else {
+                    Account::default()
+                };
+                (*id, account)
+            })
+            .collect();
+        for sysvar in sysvar_accounts.iter_mut() {
+            infos.push(AccountInfo::from(sysvar));
+        }
+        JabberInstruction::process(program_id, &infos, &instruction.try_to_vec().unwrap())
     }
 
     #[test]
-    fn test_send_message() {
-        let mut s_data = vec![0];
-        let mut r_data = vec![0];
-        let mut s_thread_data = vec![0; Thread::MIN_SPACE];
-        let mut r_thread_data = vec![0; 0];
-        let mut s_profile_data = vec![0; Profile::MIN_SPACE];
-        let mut r_profile_data = vec![0];
-        let mut msg_data = vec![0; 100];
-        let mut jabber_data = vec![0; 100];
-        let mut pks = [rand_pk(), rand_pk(), rand_pk(), rand_pk(), rand_pk()];
-        send_message(
-            String::from("Hey!"),
-            1,
-            pks,
-            &mut s_data,
-            &mut r_data,
-            &mut s_thread_data,
-            &mut r_thread_data,
-            &mut s_profile_data,
-            &mut r_profile_data,
-            &mut msg_data,
-            &mut jabber_data,
+    fn test_channel() {
+        let program_id = rand_pk();
+        let publisher_pk = rand_pk();
+        let subscriber_pk = rand_pk();
+        let channel_pk = Channel::find_address(&publisher_pk, "alerts", &program_id).0;
+        let subscription_pk =
+            Subscription::find_address(&channel_pk, &subscriber_pk, &program_id).0;
+        let msg_pk = Channel::message_address(1, &publisher_pk, &channel_pk, &program_id).0;
+        let mut publisher_lamports = 0;
+        let mut subscriber_lamports = 0;
+
+        let mut accounts = [(channel_pk, vec![0; Channel::SPACE], 0)];
+        process_with(
+            &program_id,
+            (&publisher_pk, &mut publisher_lamports),
+            &mut accounts,
+            &[rent::id(), sys_pk()],
+            JabberInstruction::CreateChannel {
+                name: String::from("alerts"),
+            },
+        )
+        .unwrap();
+        let (_, channel_data, _) = &accounts[0];
+
+        // The channel is checked before the subscription is created.
+        let mut accounts = [
+            (channel_pk, new_jabber_data(), 0),
+            (subscription_pk, vec![], 0),
+        ];
+        assert_eq!(
+            process_with(
+                &program_id,
+                (&subscriber_pk, &mut subscriber_lamports),
+                &mut accounts,
+                &[rent::id(), sys_pk()],
+                JabberInstruction::Subscribe,
+            ),
+            Err(ProgramError::InvalidAccountData)
         );
 
-        let msg = Message::unpack(&msg_data).unwrap();
-        let expected_msg = Message {
+        let mut accounts = [
+            (channel_pk, channel_data.clone(), 0),
+            (subscription_pk, vec![0; Subscription::SPACE], 100),
+        ];
+        process_with(
+            &program_id,
+            (&subscriber_pk, &mut subscriber_lamports),
+            &mut accounts,
+            &[rent::id(), sys_pk()],
+            JabberInstruction::Subscribe,
+        )
+        .unwrap();
+        assert_eq!(
+            Subscription::unpack(&accounts[1].1).unwrap(),
+            Subscription {
+                channel: channel_pk.to_bytes(),
+                subscriber: subscriber_pk.to_bytes(),
+                since: 1,
+            }
+        );
+        assert_eq!(
+            process_with(
+                &program_id,
+                (&subscriber_pk, &mut subscriber_lamports),
+                &mut accounts,
+                &[rent::id(), sys_pk()],
+                JabberInstruction::Subscribe,
+            ),
+            Err(JabberError::AccountAlreadyInitialized.into())
+        );
+
+        // Only the publisher can publish, all subscribers read the same message account.
+        let publish = || JabberInstruction::Publish {
             kind: 10,
-            msg: String::from("Hey!").into_bytes(),
-            timestamp: 0,
-        };
-        let jabber = Jabber::unpack(&jabber_data).unwrap();
-        let s_thread = Thread::unpack(&s_thread_data).unwrap();
-        let expected_s_thread = Thread {
-            msg_count: 2,
-            prev_thread_u1_pk: None,
-            prev_thread_u2_pk: None,
-            u1_pk: pks[1].to_bytes(),
-            u2_pk: pks[2].to_bytes(),
+            msg: b"Liquidation".to_vec(),
+            ttl: None,
         };
-        let thread_pk = Thread::create_with_seed(&pks[1], &pks[2], &pks[0]).unwrap();
-        assert_eq!(expected_msg, msg, "Test message");
+        let mut publish_accounts = [
+            (channel_pk, accounts[0].1.clone(), 0),
+            (msg_pk, vec![0; 100], 0),
+        ];
+        let sysvars = [rent::id(), clock::id(), sys_pk()];
         assert_eq!(
-            jabber.unregistered_thread_tail_pk,
-            Some(thread_pk.to_bytes()),
-            "Test jabber tail thread"
+            process_with(
+                &program_id,
+                (&subscriber_pk, &mut subscriber_lamports),
+                &mut publish_accounts,
+                &sysvars,
+                publish(),
+            ),
+            Err(JabberError::AccountNotAuthorized.into())
         );
-        assert_eq!(expected_s_thread, s_thread, "Test s_thread");
+        process_with(
+            &program_id,
+            (&publisher_pk, &mut publisher_lamports),
+            &mut publish_accounts,
+            &sysvars,
+            publish(),
+        )
+        .unwrap();
+        let msg = Message::unpack(&publish_accounts[1].1).unwrap();
+        assert_eq!(msg.msg, b"Liquidation".to_vec());
+        let channel = Channel::unpack(&publish_accounts[0].1).unwrap();
+        assert_eq!((channel.subscriber_count, channel.msg_count), (1, 2));
 
-        msg_data = vec![0; 100];
-        send_message(
-            String::from("What's up?"),
-            2,
-            pks,
-            &mut s_data,
-            &mut r_data,
-            &mut s_thread_data,
-            &mut r_thread_data,
-            &mut s_profile_data,
-            &mut r_profile_data,
-            &mut msg_data,
-            &mut jabber_data,
+        accounts[0].1 = publish_accounts[0].1.clone();
+        process_with(
+            &program_id,
+            (&subscriber_pk, &mut subscriber_lamports),
+            &mut accounts,
+            &[],
+            JabberInstruction::Unsubscribe,
+        )
+        .unwrap();
+        assert_eq!(subscriber_lamports, 100);
+        assert_eq!(
+            accounts[1],
+            (subscription_pk, vec![0; Subscription::SPACE], 0)
         );
+        assert_eq!(Channel::unpack(&accounts[0].1).unwrap().subscriber_count, 0);
+    }
 
-        let msg = Message::unpack(&msg_data).unwrap();
-        let expected_msg = Message {
-            kind: 10,
-            msg: String::from("What's up?").into_bytes(),
-            timestamp: 0,
+    #[test]
+    fn test_claim_threads() {
+        let program_id = rand_pk();
+        let owner = sys_pk();
+        let user_pk = rand_pk();
+        let other_pk = rand_pk();
+        let thread_pks = [rand_pk(), rand_pk(), rand_pk(), rand_pk()];
+        let thread_data = |prev: Option<&Pubkey>, u2_pk: &Pubkey| {
+            let mut data = vec![0; Thread::MIN_SPACE];
+            Thread {
+                msg_count: 2,
+                prev_thread_u1_pk: None,
+                prev_thread_u2_pk: prev.map(|pk| pk.to_bytes()),
+                unregistered: true,
+                u1_pk: rand_pk().to_bytes(),
+                u2_pk: u2_pk.to_bytes(),
+                u1_ban: false,
+                u2_ban: false,
+                u1_state: ThreadState::default(),
+                u2_state: ThreadState::default(),
+            }
+            .pack(&mut data)
+            .unwrap();
+            data
         };
-        let jabber = Jabber::unpack(&jabber_data).unwrap();
-        let s_thread = Thread::unpack(&s_thread_data).unwrap();
-        let expected_s_thread = Thread {
-            msg_count: 3,
-            prev_thread_u1_pk: None,
-            prev_thread_u2_pk: None,
-            u1_pk: pks[1].to_bytes(),
-            u2_pk: pks[2].to_bytes(),
+
+        // Unregistered list: thread 3 -> thread 2 -> thread 1 -> thread 0, threads 3 and 1
+        // belong to someone else.
+        let mut t0_data = thread_data(None, &user_pk);
+        let mut t1_data = thread_data(Some(&thread_pks[0]), &other_pk);
+        let mut t2_data = thread_data(Some(&thread_pks[1]), &user_pk);
+        let mut t3_data = thread_data(Some(&thread_pks[2]), &other_pk);
+        let mut jabber_data = new_jabber_data();
+        let mut jabber = Jabber::unpack(&jabber_data).unwrap();
+        jabber.unregistered_thread_tail_pk = Some(thread_pks[3].to_bytes());
+        jabber.pack(&mut jabber_data).unwrap();
+        let mut profile_data = vec![0; Profile::MIN_SPACE];
+        Profile::default().pack(&mut profile_data).unwrap();
+
+        let mut lamports = [0; 7];
+        let mut user_data = vec![0];
+        let profile_pk = Profile::find_address(&user_pk, &program_id).0;
+        let jabber_pk = Jabber::get_account(&program_id).unwrap();
+        let [l0, l1, l2, l3, l4, l5, l6] = &mut lamports;
+        let accounts = [
+            create_account(true, false, &user_pk, &owner, l0, &mut user_data),
+            create_account(false, true, &profile_pk, &program_id, l1, &mut profile_data),
+            create_account(false, true, &jabber_pk, &program_id, l2, &mut jabber_data),
+            create_account(false, true, &thread_pks[3], &program_id, l3, &mut t3_data),
+            create_account(false, true, &thread_pks[2], &program_id, l4, &mut t2_data),
+            create_account(false, true, &thread_pks[1], &program_id, l5, &mut t1_data),
+            create_account(false, true, &thread_pks[0], &program_id, l6, &mut t0_data),
+        ];
+        let instruction_data = JabberInstruction::ClaimThreads.try_to_vec().unwrap();
+        // Claims the threads following the predecessor, all given as indexes into `accounts`.
+        let claim = |segment: &[usize]| {
+            let accounts: Vec<_> = [0, 1]
+                .iter()
+                .chain(segment)
+                .map(|i| accounts[*i].clone())
+                .collect();
+            JabberInstruction::process(&program_id, &accounts, &instruction_data)
         };
 
-        assert_eq!(expected_msg, msg);
+        // Threads have to be passed in list order.
+        assert_eq!(claim(&[2, 5]), Err(ProgramError::InvalidArgument));
+
+        // Claim the oldest thread by starting after thread 1, then the rest from the tail.
+        claim(&[5, 6]).unwrap();
+        claim(&[2, 3, 4]).unwrap();
+        let profile = Profile::unpack(&accounts[1].data.borrow()).unwrap();
+        let jabber = Jabber::unpack(&accounts[2].data.borrow()).unwrap();
+        let t3 = Thread::unpack(&accounts[3].data.borrow()).unwrap();
+        let t2 = Thread::unpack(&accounts[4].data.borrow()).unwrap();
+        let t1 = Thread::unpack(&accounts[5].data.borrow()).unwrap();
+        let t0 = Thread::unpack(&accounts[6].data.borrow()).unwrap();
+        assert_eq!(profile.thread_tail_pk, Some(thread_pks[2].to_bytes()));
+        assert_eq!(t2.prev_thread_u2_pk, Some(thread_pks[0].to_bytes()));
+        assert_eq!(t0.prev_thread_u2_pk, None);
+        assert!(!t2.unregistered && !t0.unregistered);
         assert_eq!(
             jabber.unregistered_thread_tail_pk,
-            Some(thread_pk.to_bytes())
+            Some(thread_pks[3].to_bytes())
         );
-        assert_eq!(expected_s_thread, s_thread);
+        assert_eq!(t3.prev_thread_u2_pk, Some(thread_pks[1].to_bytes()));
+        assert_eq!(t1.prev_thread_u2_pk, None);
+        assert!(t3.unregistered && t1.unregistered);
 
-        // Send message to another account whose profile is registered
-        s_thread_data = vec![0; Thread::MIN_SPACE];
-        pks[2] = rand_pk();
-        let thread_pk = Thread::create_with_seed(&pks[1], &pks[2], &pks[0]).unwrap();
-        r_profile_data = vec![0; Profile::MIN_SPACE];
-        Profile::default().pack(&mut r_profile_data);
-        msg_data = vec![0; 100];
-        send_message(
-            String::from("bye"),
-            1,
-            pks,
-            &mut s_data,
-            &mut r_data,
-            &mut s_thread_data,
-            &mut r_thread_data,
-            &mut s_profile_data,
-            &mut r_profile_data,
-            &mut msg_data,
-            &mut jabber_data,
-        );
-        let mut expected_r_profile = Profile::default();
-        expected_r_profile.thread_tail_pk = Some(thread_pk.to_bytes());
-        let r_prrofile = Profile::unpack(&r_profile_data).unwrap();
-        assert_eq!(expected_r_profile, r_prrofile);
+        // A claimed thread can't be used as the predecessor.
+        assert_eq!(claim(&[4, 6]), Err(ProgramError::InvalidArgument));
+    }
 
-        // When Thread is available on receiver
-        r_thread_data = vec![0; Thread::MIN_SPACE];
-        Thread {
-            msg_count: 2,
-            prev_thread_u1_pk: None,
-            prev_thread_u2_pk: None,
-            u1_pk: pks[1].to_bytes(),
-            u2_pk: pks[2].to_bytes(),
+    fn new_jabber_data() -> Vec<u8> {
+        let mut data = vec![0; Jabber::SPACE];
+        Jabber {
+            unregistered_thread_tail_pk: None,
         }
-        .pack(&mut r_thread_data);
-        s_thread_data = vec![0; 0];
-        send_message(
-            String::from("Hello"),
-            2,
-            pks,
-            &mut s_data,
-            &mut r_data,
-            &mut s_thread_data,
-            &mut r_thread_data,
-            &mut s_profile_data,
-            &mut r_profile_data,
-            &mut msg_data,
-            &mut jabber_data,
-        );
-        assert_eq!(Thread::unpack(&r_thread_data).unwrap().msg_count, 3);
+        .pack(&mut data)
+        .unwrap();
+        data
+    }
 
-        // TODO: Check s_last_thread_data for all cases
+    fn test_rent() -> Rent {
+        Rent {
+            lamports_per_byte_year: 10,
+            exemption_threshold: 2.0,
+            burn_percent: 5,
+        }
+    }
+
+    fn profile_data(profile: Profile) -> Vec<u8> {
+        let mut data = vec![0; Profile::MIN_SPACE];
+        profile.pack(&mut data).unwrap();
+        data
+    }
+
+    fn message_space(msg: &[u8], prev_msg: Option<PublicKey>) -> usize {
+        Message {
+            kind: 10,
+            msg: msg.to_vec(),
+            timestamp: 0,
+            ttl: None,
+            edited: None,
+            prev_msg,
+            payer: None,
+            meta: vec![],
+        }
+        .space()
+        .unwrap()
+    }
+
+    /// Account data used by `send_message`. An empty receiver profile is not registered, an
+    /// empty message is allocated the way the program would create it.
+    struct SendMessageData {
+        thread: Vec<u8>,
+        s_profile: Vec<u8>,
+        r_profile: Vec<u8>,
+        msg: Vec<u8>,
+        jabber: Vec<u8>,
+        s_lamports: u64,
+        r_lamports: u64,
+        msg_lamports: u64,
+        /// Leaves the message account for the program to create.
+        create_msg: bool,
+        kind: u8,
+        ttl: Option<UnixTimestamp>,
+        /// Index, key and data of the message being replied to.
+        parent: Option<(u32, Pubkey, Vec<u8>)>,
+    }
+
+    impl SendMessageData {
+        fn new() -> Self {
+            Self {
+                thread: vec![0; Thread::MIN_SPACE],
+                s_profile: profile_data(Profile::default()),
+                r_profile: vec![],
+                msg: vec![],
+                jabber: new_jabber_data(),
+                s_lamports: 0,
+                r_lamports: 0,
+                msg_lamports: 0,
+                create_msg: false,
+                kind: 10,
+                ttl: None,
+                parent: None,
+            }
+        }
     }
+
+    /// Sends `msg` from `pks[1]` to `pks[2]`, `pks[0]` is the program id.
     fn send_message(
-        msg: String,
+        msg: impl AsRef<[u8]>,
         msg_index: u32,
-        pks: [Pubkey; 5],
-        mut s_data: &mut Vec<u8>,
-        mut r_data: &mut Vec<u8>,
-        mut s_thread_data: &mut Vec<u8>,
-        mut r_thread_data: &mut Vec<u8>,
-        mut s_profile_data: &mut Vec<u8>,
-        mut r_profile_data: &mut Vec<u8>,
-        mut msg_data: &mut Vec<u8>,
-        mut jabber_data: &mut Vec<u8>,
-    ) {
-        let mut pks_iter = pks.iter();
-        let program_id = pks_iter.next().unwrap();
+        pks: [Pubkey; 3],
+        data: &mut SendMessageData,
+    ) -> ProgramResult {
+        let [program_id, s_pk, r_pk] = pks;
         let owner = sys_pk();
-        let mut lamports = 0;
-        let s_pk = pks_iter.next().unwrap();
+        if data.msg.is_empty() && !data.create_msg {
+            let prev_msg = data.parent.as_ref().map(|(_, pk, _)| pk.to_bytes());
+            data.msg = vec![0; message_space(msg.as_ref(), prev_msg)];
+        }
+        let mut s_data = vec![0];
         // 0
-        let s_acc = create_account(true, true, &s_pk, &owner, &mut lamports, &mut s_data);
-        let mut lamports = 0;
-        let r_pk = pks_iter.next().unwrap();
+        let s_acc = create_account(true, true, &s_pk, &owner, &mut data.s_lamports, &mut s_data);
+        let mut r_data = vec![0];
         // 1
-        let r_acc = create_account(false, false, &r_pk, &owner, &mut lamports, &mut r_data);
-        let mut lamports = 10000000;
-        let s_thread_pk = Thread::create_with_seed(&s_pk, &r_pk, &program_id).unwrap();
-        // 2
-        let s_thread_acc = create_account(
-            false,
+        let r_acc = create_account(
             false,
-            &s_thread_pk,
-            &program_id,
-            &mut lamports,
-            &mut s_thread_data,
+            true,
+            &r_pk,
+            &owner,
+            &mut data.r_lamports,
+            &mut r_data,
         );
         let mut lamports = 10000000;
-        let r_thread_pk = Thread::create_with_seed(&r_pk, &s_pk, &program_id).unwrap();
-        // 3
-        let r_thread_acc = create_account(
-            false,
+        let thread_pk = Thread::find_address(&s_pk, &r_pk, &program_id).0;
+        // 2
+        let thread_acc = create_account(
             false,
-            &r_thread_pk,
+            true,
+            &thread_pk,
             &program_id,
             &mut lamports,
-            &mut r_thread_data,
+            &mut data.thread,
         );
         let mut lamports = 0;
-        Profile::default().pack(&mut s_profile_data);
-        let s_profile_pk = Profile::create_with_seed(&s_pk, &program_id).unwrap();
-        // 4
+        let s_profile_pk = Profile::find_address(&s_pk, &program_id).0;
+        // 3
         let s_profile_acc = create_account(
             false,
-            false,
+            true,
             &s_profile_pk,
             &program_id,
             &mut lamports,
-            &mut s_profile_data,
+            &mut data.s_profile,
         );
         let mut lamports = 0;
-        let r_profile_pk = Profile::create_with_seed(&r_pk, &program_id).unwrap();
-        // 5
+        let r_profile_pk = Profile::find_address(&r_pk, &program_id).0;
+        let r_profile_owner = if data.r_profile.is_empty() {
+            owner
+        } else {
+            program_id
+        };
+        // 4
         let r_profile_acc = create_account(
             false,
-            false,
+            true,
             &r_profile_pk,
-            &program_id,
+            &r_profile_owner,
             &mut lamports,
-            &mut r_profile_data,
+            &mut data.r_profile,
         );
 
-        let mut lamports = 0;
-        let message_pk = Message::create_with_seed(msg_index, &s_pk, &r_pk, &program_id).unwrap();
-        // 6
+        let message_pk = Message::find_address(msg_index, &s_pk, &r_pk, &program_id).0;
+        let message_owner = if data.msg.is_empty() {
+            owner
+        } else {
+            program_id
+        };
+        // 5
         let message_acc = create_account(
             false,
-            false,
+            true,
             &message_pk,
-            &owner,
-            &mut lamports,
-            &mut msg_data,
+            &message_owner,
+            &mut data.msg_lamports,
+            &mut data.msg,
         );
         let mut lamports = 0;
-        let jabber_pk = Jabber::get_account(program_id).unwrap();
-        // 7
+        let jabber_pk = Jabber::get_account(&program_id).unwrap();
+        // 6
         let jabber_acc = create_account(
             false,
-            false,
+            true,
             &jabber_pk,
-            &owner,
+            &program_id,
             &mut lamports,
-            &mut jabber_data,
+            &mut data.jabber,
         );
 
-        let rent = Rent {
-            lamports_per_byte_year: 10,
-            exemption_threshold: 2.0,
-            burn_percent: 5,
-        };
+        let rent = test_rent();
         let rent_account = rent.create_account(1);
         let rent_pubkey = solana_sdk::sysvar::rent::id();
         let mut rent_tuple = (rent_pubkey, rent_account);
-        // 8
+        // 7
         let rent_info = AccountInfo::from(&mut rent_tuple);
 
         let c = solana_sdk::clock::Clock::default();
         let clock_account = c.create_account(1);
         let clock_pubkey = solana_sdk::sysvar::clock::id();
         let mut clock_tuple = (clock_pubkey, clock_account);
+        // 8
         let clock_info = AccountInfo::from(&mut clock_tuple);
-        let accounts = [
+        let mut lamports = 0;
+        let mut system_data = vec![];
+        // 9
+        let system_acc = create_account(
+            false,
+            false,
+            &owner,
+            &owner,
+            &mut lamports,
+            &mut system_data,
+        );
+        let mut accounts = vec![
             s_acc,
             r_acc,
-            s_thread_acc,
-            r_thread_acc,
+            thread_acc,
             s_profile_acc,
             r_profile_acc,
             message_acc,
             jabber_acc,
-            rent_info.clone(),
-            clock_info.clone(),
+            rent_info,
+            clock_info,
+            system_acc,
         ];
+        let mut lamports = 0;
+        let prev_msg = match data.parent {
+            Some((index, ref pk, ref mut parent_data)) => {
+                // 10
+                accounts.push(create_account(
+                    false,
+                    false,
+                    pk,
+                    &program_id,
+                    &mut lamports,
+                    parent_data,
+                ));
+                Some(index)
+            }
+            None => None,
+        };
 
         let instruction = JabberInstruction::SendMessage {
-            kind: 10,
-            msg: msg.into_bytes(),
+            kind: data.kind,
+            msg: msg.as_ref().to_vec(),
+            ttl: data.ttl,
+            prev_msg,
         };
         JabberInstruction::process(&program_id, &accounts, &instruction.try_to_vec().unwrap())
-            .unwrap();
     }
 
     #[test]
@@ -566,8 +3531,7 @@ mod test {
             &mut lamports,
             &mut data,
         );
-        let profile_account_pk =
-            Profile::create_with_seed(&signer_account_pk, &program_id).unwrap();
+        let profile_account_pk = Profile::find_address(&signer_account_pk, &program_id).0;
         let mut profile_account_data = vec![0; 300];
         let mut lamports = 0;
         let profile_account = create_account(
@@ -578,12 +3542,25 @@ mod test {
             &mut lamports,
             &mut profile_account_data,
         );
-        let accounts = [signer_account, profile_account];
+        let mut rent_tuple = (rent::id(), test_rent().create_account(1));
+        let rent_info = AccountInfo::from(&mut rent_tuple);
+        let mut lamports = 0;
+        let mut system_data = vec![];
+        let system_account = create_account(
+            false,
+            false,
+            &owner,
+            &owner,
+            &mut lamports,
+            &mut system_data,
+        );
+        let accounts = [signer_account, profile_account, rent_info, system_account];
 
         let instruction = JabberInstruction::SetUserProfile {
             name: Some("Alpaca".into()),
             bio: Some("paca paca".into()),
             lamports_per_message: None,
+            encryption_key: None,
         };
         let instruction_data = instruction.try_to_vec().unwrap();
         JabberInstruction::process(&program_id, &accounts, &instruction_data).unwrap();
@@ -596,6 +3573,7 @@ mod test {
             name: None,
             bio: Some("hey!".into()),
             lamports_per_message: Some(10),
+            encryption_key: None,
         };
         let instruction_data = instruction.try_to_vec().unwrap();
         JabberInstruction::process(&program_id, &accounts, &instruction_data).unwrap();
@@ -603,5 +3581,32 @@ mod test {
         assert_eq!(decoded_profile.name, Some("Alpaca".into()));
         assert_eq!(decoded_profile.bio, Some("hey!".into()));
         assert_eq!(decoded_profile.lamports_per_message, 10);
+
+        // Rotating the encryption key bumps its version.
+        for key in [[1; 32], [2; 32]].iter() {
+            let instruction = JabberInstruction::SetUserProfile {
+                name: None,
+                bio: None,
+                lamports_per_message: None,
+                encryption_key: Some(*key),
+            };
+            let instruction_data = instruction.try_to_vec().unwrap();
+            JabberInstruction::process(&program_id, &accounts, &instruction_data).unwrap();
+        }
+        let decoded_profile = Profile::unpack(&accounts[1].data.try_borrow().unwrap()).unwrap();
+        assert_eq!(decoded_profile.encryption_key, Some([2; 32]));
+        assert_eq!(decoded_profile.encryption_key_version, 2);
+
+        let instruction = JabberInstruction::SetUserProfile {
+            name: None,
+            bio: Some("a".repeat(Profile::MAX_BIO_LEN + 1)),
+            lamports_per_message: None,
+            encryption_key: None,
+        };
+        let instruction_data = instruction.try_to_vec().unwrap();
+        assert_eq!(
+            JabberInstruction::process(&program_id, &accounts, &instruction_data),
+            Err(JabberError::ProfileTooLarge.into())
+        );
     }
 }
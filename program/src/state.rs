@@ -1,18 +1,70 @@
+use crate::error::JabberError;
+
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_sdk::{
     clock::UnixTimestamp,
     program_error::ProgramError,
-    pubkey::{Pubkey, PubkeyError, MAX_SEED_LEN},
+    pubkey::{Pubkey, PubkeyError},
 };
+use std::ops::Range;
 
 pub type PublicKey = [u8; 32];
+
+/// Every account starts with its `AccountKind` followed by the layout version.
+pub const HEADER_LEN: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccountKind {
+    Uninitialized = 0,
+    Profile = 1,
+    Thread = 2,
+    Message = 3,
+    Jabber = 4,
+    Group = 5,
+    Channel = 6,
+    Subscription = 7,
+}
+
 pub trait Serdes: Sized + BorshSerialize + BorshDeserialize {
-    fn pack(&self, dst: &mut [u8]) {
-        let encoded = self.try_to_vec().unwrap();
-        dst[..encoded.len()].copy_from_slice(&encoded);
+    const KIND: AccountKind;
+    /// Layout version, bumped whenever the layout of the account changes.
+    const VERSION: u8;
+
+    fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        let encoded = self
+            .try_to_vec()
+            .map_err(|_| ProgramError::InvalidArgument)?;
+        if dst.len() < HEADER_LEN + encoded.len() {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        dst[0] = Self::KIND as u8;
+        dst[1] = Self::VERSION;
+        dst[HEADER_LEN..HEADER_LEN + encoded.len()].copy_from_slice(&encoded);
+        Ok(())
     }
     fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
-        Self::try_from_slice(src).map_err(|_| ProgramError::InvalidAccountData)
+        if !Self::is_initialized(src) {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if src[0] != Self::KIND as u8 || src.len() < HEADER_LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if src[1] != Self::VERSION {
+            return Err(JabberError::AccountVersionMismatch.into());
+        }
+        Self::try_from_slice(&src[HEADER_LEN..]).map_err(|_| ProgramError::InvalidAccountData)
+    }
+    /// Whether anything has been written to the account.
+    fn is_initialized(src: &[u8]) -> bool {
+        src.first()
+            .map(|kind| *kind != AccountKind::Uninitialized as u8)
+            .unwrap_or(false)
+    }
+    /// Number of bytes needed to store the account.
+    fn packed_len(&self) -> Result<usize, ProgramError> {
+        self.try_to_vec()
+            .map(|v| HEADER_LEN + v.len())
+            .map_err(|_| ProgramError::InvalidArgument)
     }
 }
 
@@ -22,13 +74,26 @@ pub struct Profile {
     pub bio: Option<String>,
     pub lamports_per_message: u64,
     pub thread_tail_pk: Option<PublicKey>,
+    /// X25519 public key that encrypted messages are sealed to. When not set, clients convert
+    /// the user's Ed25519 key.
+    pub encryption_key: Option<PublicKey>,
+    /// Incremented every time `encryption_key` is set.
+    pub encryption_key_version: u32,
 }
 impl Profile {
-    pub const SEED: &'static str = "profile";
-    pub const MIN_SPACE: usize = 228;
+    pub const SEED: &'static [u8] = b"profile";
+    pub const MAX_NAME_LEN: usize = 50;
+    pub const MAX_BIO_LEN: usize = 160;
+    pub const MIN_SPACE: usize = HEADER_LEN
+        + (1 + 4 + Profile::MAX_NAME_LEN)
+        + (1 + 4 + Profile::MAX_BIO_LEN)
+        + 8
+        + 33
+        + 33
+        + 4;
 
-    pub fn create_with_seed(user_pk: &Pubkey, program_id: &Pubkey) -> Result<Pubkey, PubkeyError> {
-        Pubkey::create_with_seed(&user_pk, Profile::SEED, &program_id)
+    pub fn find_address(user_pk: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[Profile::SEED, user_pk.as_ref()], program_id)
     }
 }
 impl Default for Profile {
@@ -38,69 +103,551 @@ impl Default for Profile {
             bio: None,
             lamports_per_message: 0,
             thread_tail_pk: None,
+            encryption_key: None,
+            encryption_key_version: 0,
         }
     }
 }
-impl Serdes for Profile {}
+impl Serdes for Profile {
+    const KIND: AccountKind = AccountKind::Profile;
+    const VERSION: u8 = 2;
+}
 
 #[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
 pub struct Thread {
     pub msg_count: u32,
     pub prev_thread_u1_pk: Option<PublicKey>,
     pub prev_thread_u2_pk: Option<PublicKey>,
+    /// Set while the thread is on the unregistered thread list of the Jabber account.
+    pub unregistered: bool,
     pub u1_pk: PublicKey,
     pub u2_pk: PublicKey,
+    /// Set by u1 to stop receiving messages on this thread.
+    pub u1_ban: bool,
+    /// Set by u2 to stop receiving messages on this thread.
+    pub u2_ban: bool,
+    pub u1_state: ThreadState,
+    pub u2_state: ThreadState,
+}
+impl Serdes for Thread {
+    const KIND: AccountKind = AccountKind::Thread;
+    const VERSION: u8 = 2;
 }
-impl Serdes for Thread {}
 impl Thread {
-    pub const MIN_SPACE: usize = 134;
-    pub fn create_with_seed(
-        creator_pk: &Pubkey,
-        friend_pk: &Pubkey,
-        program_id: &Pubkey,
-    ) -> Result<Pubkey, PubkeyError> {
-        Pubkey::create_with_seed(
-            creator_pk,
-            &friend_pk.to_string()[..MAX_SEED_LEN],
-            program_id,
-        )
+    pub const SEED: &'static [u8] = b"thread";
+    pub const MIN_SPACE: usize = 151;
+
+    /// Both users derive the same thread account, regardless of who sent the first message.
+    pub fn find_address(u1_pk: &Pubkey, u2_pk: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+        let (a, b) = Thread::ordered_pks(u1_pk, u2_pk);
+        Pubkey::find_program_address(&[Thread::SEED, a.as_ref(), b.as_ref()], program_id)
+    }
+
+    /// The user keys in the order they are used as seeds.
+    pub fn ordered_pks<'a>(u1_pk: &'a Pubkey, u2_pk: &'a Pubkey) -> (&'a Pubkey, &'a Pubkey) {
+        if u1_pk <= u2_pk {
+            (u1_pk, u2_pk)
+        } else {
+            (u2_pk, u1_pk)
+        }
     }
 }
 
+/// Chat state of a thread, only changed by the user it belongs to.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ThreadState {
+    /// Index of the last message read by the user.
+    pub last_read: u32,
+    pub muted: bool,
+    pub archived: bool,
+}
+
 #[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq)]
 pub struct Message {
     pub kind: u8,
     pub msg: Vec<u8>,
     pub timestamp: UnixTimestamp,
+    /// Seconds after `timestamp` when anyone can close the message. Only the sender can close
+    /// it when this is not set.
+    pub ttl: Option<UnixTimestamp>,
+    /// Time of the last edit by the sender.
+    pub edited: Option<UnixTimestamp>,
+    /// The message being replied to.
+    pub prev_msg: Option<PublicKey>,
+    /// Account that funded the message when it is not the sender, refunded on close.
+    pub payer: Option<PublicKey>,
+    /// Reactions and read receipts added by the thread participants.
+    pub meta: Vec<MessageMeta>,
 }
 impl Message {
-    pub fn create_with_seed(
+    pub const SEED: &'static [u8] = b"message";
+    pub const TEXT_KIND: u8 = 0;
+    pub const CTA_KIND: u8 = 1;
+    pub const PLAIN_KIND: u8 = 2;
+    pub const ENCRYPTED_KIND: u8 = 3;
+    /// Kind of a message retracted by its sender, the payload is removed.
+    pub const RETRACTED_KIND: u8 = 4;
+    /// Kinds from here on are application defined, their payload is not checked.
+    pub const CUSTOM_KIND_START: u8 = 10;
+    pub const MAX_META_PER_USER: usize = 4;
+    /// Space reserved on the message account for `meta` entries of both users.
+    pub const META_SPACE: usize = 2 * Message::MAX_META_PER_USER * MessageMeta::SPACE;
+
+    pub fn find_address(
         index: u32,
         from_pk: &Pubkey,
         to_pk: &Pubkey,
         program_id: &Pubkey,
-    ) -> Result<Pubkey, PubkeyError> {
-        let i = index.to_string();
-        let end = MAX_SEED_LEN - i.len();
-        Pubkey::create_with_seed(
-            from_pk,
-            &[i, to_pk.to_string()[..end].to_string()].concat(),
+    ) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[
+                Message::SEED,
+                from_pk.as_ref(),
+                to_pk.as_ref(),
+                &index.to_le_bytes(),
+            ],
             program_id,
         )
     }
+
+    /// Account space for the message, with room for an edit timestamp and all `meta` entries.
+    pub fn space(&self) -> Result<usize, ProgramError> {
+        let meta_len = self
+            .meta
+            .try_to_vec()
+            .map(|v| v.len() - 4)
+            .map_err(|_| ProgramError::InvalidArgument)?;
+        let edited_len = if self.edited.is_some() { 0 } else { 8 };
+        Ok(self.packed_len()? - meta_len + edited_len + Message::META_SPACE)
+    }
+
+    /// Checks that the payload of a protocol defined `kind` is valid at `now`.
+    pub fn check_payload(kind: u8, msg: &[u8], now: UnixTimestamp) -> Result<(), ProgramError> {
+        if kind >= Message::CUSTOM_KIND_START {
+            return Ok(());
+        }
+        match MessagePayload::unpack(kind, msg)? {
+            MessagePayload::Cta(cta) if cta.delivery < now => {
+                Err(JabberError::DeliveryInPast.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that `ttl`, if set, leaves the message some time before it expires.
+    pub fn check_ttl(ttl: Option<UnixTimestamp>) -> Result<(), ProgramError> {
+        match ttl {
+            Some(ttl) if ttl <= 0 => Err(JabberError::InvalidTtl.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_expired(&self, now: UnixTimestamp) -> bool {
+        match self.ttl {
+            Some(ttl) => self.timestamp.saturating_add(ttl) <= now,
+            None => false,
+        }
+    }
 }
-impl Serdes for Message {}
-#[derive(BorshSerialize, BorshDeserialize, Debug)]
+impl Serdes for Message {
+    const KIND: AccountKind = AccountKind::Message;
+    const VERSION: u8 = 5;
+}
+
+/// Payload of the message kinds defined by the protocol, borsh encoded in `Message.msg`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MessagePayload {
+    Text(String),
+    Cta(CtaMessage),
+    Plain(Vec<u8>),
+    Encrypted(EncryptedMessage),
+}
+impl MessagePayload {
+    pub fn kind(&self) -> u8 {
+        match self {
+            MessagePayload::Text(_) => Message::TEXT_KIND,
+            MessagePayload::Cta(_) => Message::CTA_KIND,
+            MessagePayload::Plain(_) => Message::PLAIN_KIND,
+            MessagePayload::Encrypted(_) => Message::ENCRYPTED_KIND,
+        }
+    }
+
+    pub fn pack(&self) -> Result<Vec<u8>, ProgramError> {
+        match self {
+            MessagePayload::Text(text) => text.try_to_vec(),
+            MessagePayload::Cta(cta) => cta.try_to_vec(),
+            MessagePayload::Plain(data) => data.try_to_vec(),
+            MessagePayload::Encrypted(encrypted) => encrypted.try_to_vec(),
+        }
+        .map_err(|_| ProgramError::InvalidArgument)
+    }
+
+    /// Decodes the payload of `kind`, all of `src` has to be used.
+    pub fn unpack(kind: u8, src: &[u8]) -> Result<Self, ProgramError> {
+        fn decode<T: BorshDeserialize>(mut src: &[u8]) -> Result<T, ProgramError> {
+            let value = T::deserialize(&mut src)
+                .map_err(|_| ProgramError::from(JabberError::InvalidMessagePayload))?;
+            if !src.is_empty() {
+                return Err(JabberError::InvalidMessagePayload.into());
+            }
+            Ok(value)
+        }
+        match kind {
+            Message::TEXT_KIND => decode(src).map(MessagePayload::Text),
+            Message::CTA_KIND => decode(src).map(MessagePayload::Cta),
+            Message::PLAIN_KIND => decode(src).map(MessagePayload::Plain),
+            Message::ENCRYPTED_KIND => decode(src).map(MessagePayload::Encrypted),
+            _ => Err(JabberError::UnsupportedMessageKind.into()),
+        }
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct EncryptedMessage {
+    /// `Profile.encryption_key_version` of the receiver key the message is sealed to, 0 if it
+    /// is sealed to the converted Ed25519 key.
+    pub key_version: u32,
+    pub data: Vec<u8>,
+}
+
+/// Call to action, shown by push notification nodes once `delivery` has passed.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct CtaMessage {
+    pub title: String,
+    pub body: String,
+    pub delivery: UnixTimestamp,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct MessageMeta {
+    /// Added by the sender of the message, otherwise by the receiver.
+    pub by_sender: bool,
+    pub kind: MetaKind,
+    pub timestamp: UnixTimestamp,
+}
+impl MessageMeta {
+    pub const SPACE: usize = 1 + (1 + 4) + 8;
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum MetaKind {
+    /// Unicode code point of the reaction emoji.
+    Reaction(u32),
+    Read,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
 pub struct Jabber {
     pub unregistered_thread_tail_pk: Option<PublicKey>,
 }
-impl Serdes for Jabber {}
+impl Serdes for Jabber {
+    const KIND: AccountKind = AccountKind::Jabber;
+    const VERSION: u8 = 2;
+}
 impl Jabber {
+    pub const SEED: &'static str = "jabber";
+    pub const SPACE: usize = HEADER_LEN + 33;
+
     pub fn get_account(program_id: &Pubkey) -> Result<Pubkey, PubkeyError> {
-        Pubkey::create_with_seed(&owner_account::id(), "jabber", program_id)
+        Pubkey::create_with_seed(&owner_account::id(), Jabber::SEED, program_id)
+    }
+}
+/// Group chat with a fixed number of message slots after the group state. Once all slots are
+/// used, new messages overwrite the oldest one.
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct Group {
+    pub creator: PublicKey,
+    /// Manages admins, and has every right of an admin.
+    pub owner: PublicKey,
+    /// Manage members and posters, and can always post.
+    pub admins: Vec<PublicKey>,
+    /// Can post when the policy is `PostPolicy::Posters`.
+    pub posters: Vec<PublicKey>,
+    /// Can post when the policy is `PostPolicy::Members`, otherwise they can only read.
+    pub members: Vec<PublicKey>,
+    pub post_policy: PostPolicy,
+    pub max_msg_len: u32,
+    /// Number of messages the account can hold.
+    pub capacity: u32,
+    /// Number of messages ever posted, the next message is written to slot
+    /// `msg_count % capacity`.
+    pub msg_count: u64,
+}
+impl Serdes for Group {
+    const KIND: AccountKind = AccountKind::Group;
+    const VERSION: u8 = 2;
+}
+impl Group {
+    pub const MAX_ADMINS: usize = 8;
+    pub const MAX_POSTERS: usize = 32;
+    pub const MAX_MEMBERS: usize = 256;
+    /// Space reserved for the group state, message slots start after it.
+    pub const SPACE: usize = HEADER_LEN
+        + 32
+        + 32
+        + (4 + 32 * Group::MAX_ADMINS)
+        + (4 + 32 * Group::MAX_POSTERS)
+        + (4 + 32 * Group::MAX_MEMBERS)
+        + 1
+        + 4
+        + 4
+        + 8;
+
+    /// Space of a single message slot.
+    pub fn slot_space(max_msg_len: u32) -> usize {
+        GroupMessage::FIXED_SPACE + max_msg_len as usize
+    }
+
+    /// Number of slots that fit an account of `data_len` bytes.
+    pub fn capacity_for(data_len: usize, max_msg_len: u32) -> usize {
+        data_len.saturating_sub(Group::SPACE) / Group::slot_space(max_msg_len)
+    }
+
+    pub fn is_admin(&self, pk: &PublicKey) -> bool {
+        self.owner == *pk || self.admins.contains(pk)
+    }
+
+    pub fn is_poster(&self, pk: &PublicKey) -> bool {
+        self.is_admin(pk) || self.posters.contains(pk)
+    }
+
+    /// Users with a role are members as well.
+    pub fn is_member(&self, pk: &PublicKey) -> bool {
+        self.is_poster(pk) || self.members.contains(pk)
+    }
+
+    /// Checks that the posting policy allows `pk` to post.
+    pub fn check_post(&self, pk: &PublicKey) -> Result<(), ProgramError> {
+        let allowed = match self.post_policy {
+            PostPolicy::Members => self.is_member(pk),
+            PostPolicy::Posters => self.is_poster(pk),
+            PostPolicy::Admins => self.is_admin(pk),
+        };
+        if allowed {
+            Ok(())
+        } else if self.is_member(pk) {
+            Err(JabberError::GroupPostingRestricted.into())
+        } else {
+            Err(JabberError::NotGroupMember.into())
+        }
+    }
+
+    /// The users with `role`, and the maximum number of them.
+    pub fn role_list(&mut self, role: GroupRole) -> (&mut Vec<PublicKey>, usize) {
+        match role {
+            GroupRole::Admin => (&mut self.admins, Group::MAX_ADMINS),
+            GroupRole::Poster => (&mut self.posters, Group::MAX_POSTERS),
+        }
+    }
+
+    /// Location of message `index` in the account data.
+    pub fn slot_range(&self, index: u64) -> Range<usize> {
+        let slot_space = Group::slot_space(self.max_msg_len);
+        let start = Group::SPACE + (index % self.capacity as u64) as usize * slot_space;
+        start..start + slot_space
+    }
+
+    /// Reads message `index`, `None` if it has not been posted yet or was overwritten.
+    pub fn read_message(
+        &self,
+        src: &[u8],
+        index: u64,
+    ) -> Result<Option<GroupMessage>, ProgramError> {
+        if index >= self.msg_count || index + (self.capacity as u64) < self.msg_count {
+            return Ok(None);
+        }
+        let slot = src
+            .get(self.slot_range(index))
+            .ok_or(ProgramError::AccountDataTooSmall)?;
+        GroupMessage::try_from_slice(slot)
+            .map(Some)
+            .map_err(|_| ProgramError::InvalidAccountData)
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum GroupRole {
+    Admin,
+    Poster,
+}
+
+/// Who can post to a group.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PostPolicy {
+    Members,
+    /// Posters, admins and the owner. Used for announcement channels.
+    Posters,
+    Admins,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct GroupMessage {
+    pub sender: PublicKey,
+    pub kind: u8,
+    pub timestamp: UnixTimestamp,
+    pub msg: Vec<u8>,
+}
+impl GroupMessage {
+    /// Space of the message without the `msg` bytes.
+    pub const FIXED_SPACE: usize = 32 + 1 + 8 + 4;
+}
+
+/// PubSub channel. Published messages are `Message` accounts sent from the publisher to the
+/// channel, so any number of subscribers read the same account.
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct Channel {
+    pub publisher: PublicKey,
+    pub name: String,
+    pub subscriber_count: u32,
+    /// Index of the next published message, starting from 1.
+    pub msg_count: u32,
+}
+impl Serdes for Channel {
+    const KIND: AccountKind = AccountKind::Channel;
+    const VERSION: u8 = 1;
+}
+impl Channel {
+    pub const SEED: &'static [u8] = b"channel";
+    /// The name is used as a seed.
+    pub const MAX_NAME_LEN: usize = 32;
+    pub const SPACE: usize = HEADER_LEN + 32 + (4 + Channel::MAX_NAME_LEN) + 4 + 4;
+
+    pub fn find_address(publisher_pk: &Pubkey, name: &str, program_id: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[Channel::SEED, publisher_pk.as_ref(), name.as_bytes()],
+            program_id,
+        )
     }
+
+    /// Address of published message `index`.
+    pub fn message_address(
+        index: u32,
+        publisher_pk: &Pubkey,
+        channel_pk: &Pubkey,
+        program_id: &Pubkey,
+    ) -> (Pubkey, u8) {
+        Message::find_address(index, publisher_pk, channel_pk, program_id)
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct Subscription {
+    pub channel: PublicKey,
+    pub subscriber: PublicKey,
+    /// Index of the first message published after subscribing.
+    pub since: u32,
+}
+impl Serdes for Subscription {
+    const KIND: AccountKind = AccountKind::Subscription;
+    const VERSION: u8 = 1;
 }
+impl Subscription {
+    pub const SEED: &'static [u8] = b"subscription";
+    pub const SPACE: usize = HEADER_LEN + 32 + 32 + 4;
+
+    pub fn find_address(
+        channel_pk: &Pubkey,
+        subscriber_pk: &Pubkey,
+        program_id: &Pubkey,
+    ) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[
+                Subscription::SEED,
+                channel_pk.as_ref(),
+                subscriber_pk.as_ref(),
+            ],
+            program_id,
+        )
+    }
+}
+
 pub mod owner_account {
     use solana_sdk::declare_id;
     declare_id!("D2T7LaEp7SgQCZWvxbMfWym6LW2cSfX69oXpFLCDqbVS");
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_account_header() {
+        let mut data = vec![0; Thread::MIN_SPACE];
+        assert_eq!(
+            Thread::unpack(&data),
+            Err(ProgramError::UninitializedAccount)
+        );
+
+        let thread = Thread {
+            msg_count: 1,
+            prev_thread_u1_pk: None,
+            prev_thread_u2_pk: None,
+            unregistered: false,
+            u1_pk: [1; 32],
+            u2_pk: [2; 32],
+            u1_ban: false,
+            u2_ban: false,
+            u1_state: ThreadState::default(),
+            u2_state: ThreadState::default(),
+        };
+        thread.pack(&mut data).unwrap();
+        assert_eq!(
+            data[..HEADER_LEN],
+            [AccountKind::Thread as u8, Thread::VERSION]
+        );
+        assert_eq!(
+            thread.pack(&mut [0; HEADER_LEN + 4]),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+        assert_eq!(Thread::unpack(&data), Ok(thread));
+        assert_eq!(
+            Profile::unpack(&data),
+            Err(ProgramError::InvalidAccountData)
+        );
+
+        data[1] = Thread::VERSION + 1;
+        assert_eq!(
+            Thread::unpack(&data),
+            Err(JabberError::AccountVersionMismatch.into())
+        );
+    }
+
+    #[test]
+    fn test_message_payload() {
+        let cta = MessagePayload::Cta(CtaMessage {
+            title: String::from("Liquidation"),
+            body: String::from("Your loan is at risk"),
+            delivery: 100,
+        });
+        let mut data = cta.pack().unwrap();
+        assert_eq!(MessagePayload::unpack(cta.kind(), &data), Ok(cta.clone()));
+        assert_eq!(Message::check_payload(cta.kind(), &data, 100), Ok(()));
+        assert_eq!(
+            Message::check_payload(cta.kind(), &data, 101),
+            Err(JabberError::DeliveryInPast.into())
+        );
+
+        // The payload has to match the layout of its kind exactly.
+        data.push(0);
+        assert_eq!(
+            MessagePayload::unpack(cta.kind(), &data),
+            Err(JabberError::InvalidMessagePayload.into())
+        );
+        assert_eq!(
+            MessagePayload::unpack(Message::TEXT_KIND, &[5, 0, 0, 0, b'H']),
+            Err(JabberError::InvalidMessagePayload.into())
+        );
+        assert_eq!(
+            MessagePayload::unpack(Message::TEXT_KIND, &[1, 0, 0, 0, b'H']),
+            Ok(MessagePayload::Text(String::from("H")))
+        );
+        assert_eq!(
+            Message::check_payload(Message::RETRACTED_KIND, &[], 0),
+            Err(JabberError::UnsupportedMessageKind.into())
+        );
+        assert_eq!(
+            Message::check_payload(Message::CUSTOM_KIND_START, &[1, 2, 3], 0),
+            Ok(())
+        );
+    }
+}